@@ -8,9 +8,24 @@ create table if not exists crates
 
 comment on column crates.name is 'crate names are limited to 64 characters, see https://github.com/rust-lang/crates.io/pull/718';
 
+-- crates from registries other than crates.io are stored as `registry:crate`
+alter table crates
+  alter column name type varchar(128);
+
+comment on column crates.name is 'crate name, prefixed with `registry:` for registries other than crates.io (crate names are limited to 64 characters, see https://github.com/rust-lang/crates.io/pull/718)';
+
 create unique index if not exists crates_name_uindex
   on crates (name);
 
+-- crates.io doesn't allow names which only differ in case or `-`/`_`, so such names refer to the same crate
+create or replace function normalize_crate_name(_name varchar)
+    RETURNS varchar
+    LANGUAGE sql
+    IMMUTABLE
+AS $$
+    select lower(replace(_name, '_', '-'))
+$$;
+
 create table if not exists subscriptions
 (
   user_id bigint not null,
@@ -31,18 +46,73 @@ alter table subscriptions
     foreign key (crate_id) references crates
       on delete cascade;
 
-create or replace procedure subscribe(_user_id bigint, _crate varchar(64))
+alter table subscriptions
+  add column if not exists req varchar;
+
+comment on column subscriptions.req is 'semver version requirement (e.g. `^1`), null means "any version"';
+
+alter table subscriptions
+  add column if not exists new_versions boolean not null default true,
+  add column if not exists yanks boolean not null default true,
+  add column if not exists unyanks boolean not null default true,
+  add column if not exists prereleases boolean not null default true;
+
+comment on column subscriptions.prereleases is 'whether to notify about pre-release versions (e.g. `1.0.0-rc.1`)';
+
+alter table subscriptions
+  add column if not exists lockfile boolean not null default false;
+
+comment on column subscriptions.lockfile is 'whether the subscription was made from an uploaded `Cargo.lock` (such subscriptions are replaced on re-upload)';
+
+alter table subscriptions
+  add column if not exists muted_until timestamptz;
+
+comment on column subscriptions.muted_until is 'updates are not reported until this time, null if the subscription is not muted';
+
+drop procedure if exists subscribe(bigint, varchar);
+
+create or replace procedure subscribe(_user_id bigint, _crate varchar(64), _req varchar)
     LANGUAGE plpgsql
 AS $$
 begin
-    if not exists (select * from crates where crates.name = _crate) then
+    if not exists (select * from crates where normalize_crate_name(crates.name) = normalize_crate_name(_crate)) then
         insert into crates (name) values (_crate) on conflict do nothing;
+    else
+        -- `_crate` is the exact name from the index, fix names stored with a different case or separators
+        update crates set name = _crate
+            where normalize_crate_name(name) = normalize_crate_name(_crate) and name <> _crate;
     end if;
 
-    insert into subscriptions (user_id, crate_id)
-        select _user_id, id from crates
-            where crates.name = _crate
-        on conflict do nothing;
+    insert into subscriptions (user_id, crate_id, req)
+        select _user_id, id, _req from crates
+            where normalize_crate_name(crates.name) = normalize_crate_name(_crate)
+        -- subscribing manually "detaches" the subscription from the lockfile and unmutes it
+        on conflict (crate_id, user_id) do update set req = excluded.req, lockfile = false, muted_until = null;
+end
+$$;
+
+create or replace function set_filter(
+    _user_id bigint,
+    _crate varchar(64),
+    _new_versions boolean,
+    _yanks boolean,
+    _unyanks boolean,
+    _prereleases boolean
+)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    update subscriptions
+        set new_versions = _new_versions,
+            yanks = _yanks,
+            unyanks = _unyanks,
+            prereleases = _prereleases
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and user_id = _user_id;
+
+    -- `false` if the user is not subscribed to the crate
+    RETURN found;
 end
 $$;
 
@@ -51,31 +121,866 @@ create or replace procedure unsubscribe(_user_id bigint, _crate varchar(64))
 AS $$
 begin
     delete from subscriptions
-        where crate_id = (select id from crates where name = _crate)
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
             and user_id = _user_id;
 end
 $$;
 
+create or replace procedure delete_crate(_crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    -- subscriptions are deleted by `on delete cascade`
+    delete from crates where normalize_crate_name(name) = normalize_crate_name(_crate);
+end
+$$;
+
+drop function if exists list_subscriptions(bigint);
+
 create or replace function list_subscriptions(_user_id bigint)
-RETURNS TABLE(crate_name varchar(64))
+RETURNS TABLE(crate_name varchar(64), req varchar,
+        new_versions boolean, yanks boolean, unyanks boolean, prereleases boolean)
     LANGUAGE plpgsql
 AS $$
 begin
-    RETURN QUERY select c.name as crate_name
+    RETURN QUERY select c.name as crate_name, s.req as req,
+            s.new_versions, s.yanks, s.unyanks, s.prereleases
         from subscriptions as s
             inner join crates as c on c.id = s.crate_id
         where s.user_id = _user_id;
 end
 $$;
 
+drop function if exists list_subscribers(varchar);
+
 create or replace function list_subscribers(_crate varchar(64))
-    RETURNS TABLE(user_id bigint)
+    RETURNS TABLE(user_id bigint, req varchar,
+        new_versions boolean, yanks boolean, unyanks boolean, prereleases boolean, muted boolean,
+        digest boolean, quiet varchar)
     LANGUAGE plpgsql
 AS $$
 begin
-    RETURN QUERY select s.user_id as user_id
+    RETURN QUERY select s.user_id as user_id, s.req as req,
+            s.new_versions, s.yanks, s.unyanks, s.prereleases,
+            coalesce(s.muted_until > now(), false) as muted,
+            coalesce(ch.delivery <> 'instant', false) as digest,
+            -- `quiet_mode` if the chat is in quiet hours right now
+            case when quiet_until(ch.quiet_start, ch.quiet_end, ch.timezone, now()) is not null
+                then ch.quiet_mode end as quiet
          from subscriptions as s
               inner join crates as c on c.id = s.crate_id
-         where c.name = _crate;
+              left join chats as ch on ch.user_id = s.user_id
+         where normalize_crate_name(c.name) = normalize_crate_name(_crate)
+            -- chats which blocked or removed the bot are skipped
+            and coalesce(ch.status, 'active') = 'active';
+end
+$$;
+
+create or replace function mute(_user_id bigint, _crate varchar, _days int)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    update subscriptions
+        set muted_until = now() + make_interval(days => _days)
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and user_id = _user_id;
+
+    -- `false` if the user is not subscribed to the crate
+    RETURN found;
+end
+$$;
+
+create or replace function unmute(_user_id bigint, _crate varchar)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    update subscriptions
+        set muted_until = null
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and user_id = _user_id;
+
+    -- `false` if the user is not subscribed to the crate
+    RETURN found;
+end
+$$;
+
+create or replace function list_crates()
+    RETURNS TABLE(crate_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.name as crate_name
+         from crates as c
+         where exists (select * from subscriptions as s where s.crate_id = c.id)
+            or exists (select * from watched_versions as w where w.crate_id = c.id);
+end
+$$;
+
+create table if not exists index_cursors
+(
+  registry varchar not null
+    constraint index_cursors_pk
+      primary key,
+  commit_oid varchar(40) not null
+);
+
+comment on table index_cursors is 'the last commit of a git index which updates were fully processed';
+
+create or replace procedure set_cursor(_registry varchar, _commit_oid varchar(40))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into index_cursors (registry, commit_oid) values (_registry, _commit_oid)
+        on conflict (registry) do update set commit_oid = excluded.commit_oid;
+end
+$$;
+
+create table if not exists sparse_files
+(
+  registry varchar not null,
+  crate_name varchar not null,
+  state varchar not null,
+  constraint sparse_files_pk
+    primary key (registry, crate_name)
+);
+
+-- files were stored only for crates.io before there were several registries
+alter table sparse_files
+  add column if not exists registry varchar not null default 'crates-io';
+
+alter table sparse_files
+  alter column registry drop default;
+
+alter table sparse_files
+  drop constraint if exists sparse_files_pk;
+
+alter table sparse_files
+  add constraint sparse_files_pk
+    primary key (registry, crate_name);
+
+comment on table sparse_files is 'the last seen state of crate files of sparse indexes, updates are found by comparing to it';
+
+comment on column sparse_files.state is 'etag and index entries of all versions in the file (json)';
+
+-- the state of crate files is set by record_events, together with the updates found in them
+drop procedure if exists set_sparse_file(varchar, varchar);
+
+drop procedure if exists set_sparse_file(varchar, varchar, varchar);
+
+create table if not exists watched_versions
+(
+  user_id bigint not null,
+  crate_id int not null
+    constraint watched_versions_crates_id_fk
+      references crates
+        on delete cascade,
+  version varchar not null,
+  lockfile boolean not null default false,
+  constraint watched_versions_pk
+    primary key (crate_id, version, user_id)
+);
+
+comment on table watched_versions is 'exact versions chats depend on, chats are alerted when they are yanked';
+
+comment on column watched_versions.lockfile is 'whether the version is from an uploaded `Cargo.lock` (such versions are replaced on re-upload)';
+
+create index if not exists watched_versions_user_id_index
+  on watched_versions (user_id);
+
+create or replace procedure watch(_user_id bigint, _crate varchar, _version varchar)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into crates (name) values (_crate) on conflict do nothing;
+
+    insert into watched_versions (user_id, crate_id, version)
+        select _user_id, id, _version from crates
+            where normalize_crate_name(crates.name) = normalize_crate_name(_crate)
+        on conflict (crate_id, version, user_id) do update set lockfile = false;
+end
+$$;
+
+create or replace function unwatch(_user_id bigint, _crate varchar, _version varchar)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from watched_versions
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and version = _version
+            and user_id = _user_id;
+
+    -- `false` if the chat didn't watch the version
+    RETURN found;
+end
+$$;
+
+create or replace function list_watchers(_crate varchar, _version varchar)
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select w.user_id as user_id
+         from watched_versions as w
+              inner join crates as c on c.id = w.crate_id
+              left join chats as ch on ch.user_id = w.user_id
+         where normalize_crate_name(c.name) = normalize_crate_name(_crate) and w.version = _version
+            and coalesce(ch.status, 'active') = 'active';
+end
+$$;
+
+drop function if exists replace_lockfile(bigint, varchar[], varchar[]);
+
+create or replace function replace_lockfile(
+    _user_id bigint,
+    _crates varchar[],
+    _reqs varchar[],
+    _watched_crates varchar[],
+    _watched_versions varchar[]
+)
+    RETURNS TABLE(added bigint, updated bigint, removed bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into crates (name) select unnest(_crates) on conflict do nothing;
+
+    -- subscriptions made manually are left as is
+    delete from subscriptions as s
+        using crates as c
+        where c.id = s.crate_id
+            and s.user_id = _user_id
+            and s.lockfile
+            and not exists (
+                select * from unnest(_crates) as l(name)
+                    where normalize_crate_name(l.name) = normalize_crate_name(c.name)
+            );
+    get diagnostics removed = row_count;
+
+    update subscriptions as s
+        set req = l.req
+        from unnest(_crates, _reqs) as l(name, req)
+            inner join crates as c on normalize_crate_name(c.name) = normalize_crate_name(l.name)
+        where s.crate_id = c.id
+            and s.user_id = _user_id
+            and s.lockfile
+            and s.req is distinct from l.req;
+    get diagnostics updated = row_count;
+
+    insert into subscriptions (user_id, crate_id, req, lockfile)
+        select _user_id, c.id, l.req, true
+            from unnest(_crates, _reqs) as l(name, req)
+                inner join crates as c on normalize_crate_name(c.name) = normalize_crate_name(l.name)
+        on conflict (crate_id, user_id) do nothing;
+    get diagnostics added = row_count;
+
+    -- locked versions are replaced completely
+    delete from watched_versions where user_id = _user_id and lockfile;
+
+    insert into crates (name) select unnest(_watched_crates) on conflict do nothing;
+
+    insert into watched_versions (user_id, crate_id, version, lockfile)
+        select _user_id, c.id, l.version, true
+            from unnest(_watched_crates, _watched_versions) as l(name, version)
+                inner join crates as c on normalize_crate_name(c.name) = normalize_crate_name(l.name)
+        on conflict (crate_id, version, user_id) do nothing;
+
+    RETURN NEXT;
+end
+$$;
+
+-- merge crates which were stored under names differing only in case or `-`/`_` into the oldest row, a subscription
+-- stays muted if any of the merged ones was
+insert into subscriptions (user_id, crate_id, req, new_versions, yanks, unyanks, prereleases, lockfile, muted_until)
+    select distinct on (s.user_id, keep.id)
+            s.user_id, keep.id, s.req, s.new_versions, s.yanks, s.unyanks, s.prereleases, s.lockfile,
+            max(s.muted_until) over (partition by s.user_id, keep.id)
+        from subscriptions as s
+            inner join crates as dup on dup.id = s.crate_id
+            inner join crates as keep
+                on normalize_crate_name(keep.name) = normalize_crate_name(dup.name) and keep.id < dup.id
+        where not exists (
+            select * from crates as older
+                where normalize_crate_name(older.name) = normalize_crate_name(keep.name) and older.id < keep.id
+        )
+        order by s.user_id, keep.id, dup.id
+    on conflict (crate_id, user_id) do update
+        set muted_until = greatest(subscriptions.muted_until, excluded.muted_until);
+
+insert into watched_versions (user_id, crate_id, version, lockfile)
+    select w.user_id, keep.id, w.version, w.lockfile
+        from watched_versions as w
+            inner join crates as dup on dup.id = w.crate_id
+            inner join crates as keep
+                on normalize_crate_name(keep.name) = normalize_crate_name(dup.name) and keep.id < dup.id
+        where not exists (
+            select * from crates as older
+                where normalize_crate_name(older.name) = normalize_crate_name(keep.name) and older.id < keep.id
+        )
+    on conflict (crate_id, version, user_id) do nothing;
+
+-- subscriptions of the duplicates are deleted by `on delete cascade`
+delete from crates as dup
+    using crates as keep
+    where normalize_crate_name(keep.name) = normalize_crate_name(dup.name) and keep.id < dup.id;
+
+create unique index if not exists crates_normalized_name_uindex
+  on crates (normalize_crate_name(name));
+
+create table if not exists chats
+(
+  user_id bigint not null
+    constraint chats_pk
+      primary key,
+  delivery varchar not null default 'instant',
+  digest_hour int not null default 9,
+  next_digest timestamptz
+);
+
+comment on table chats is 'per-chat settings, chats without a row use the defaults';
+
+comment on column chats.delivery is 'how updates are delivered: `instant`, `daily` or `weekly` (digests)';
+
+comment on column chats.digest_hour is 'hour (in the chat timezone) at which digests are sent';
+
+comment on column chats.next_digest is 'when queued updates should be sent next, null if there is no digest scheduled';
+
+create table if not exists digest_queue
+(
+  id bigserial not null
+    constraint digest_queue_pk
+      primary key,
+  user_id bigint not null,
+  crate_name varchar not null,
+  version varchar not null,
+  action varchar not null,
+  created_at timestamptz not null default now()
+);
+
+comment on table digest_queue is 'updates waiting to be sent in a digest';
+
+comment on column digest_queue.crate_name is 'the crate is referred to by name, so that queued deletions outlive the crate';
+
+create index if not exists digest_queue_user_id_index
+  on digest_queue (user_id);
+
+alter table chats
+  add column if not exists timezone varchar not null default 'UTC',
+  add column if not exists quiet_start int,
+  add column if not exists quiet_end int,
+  add column if not exists quiet_mode varchar not null default 'silent';
+
+comment on column chats.timezone is 'name of the chat timezone (from `pg_timezone_names`)';
+
+comment on column chats.quiet_start is 'hour at which quiet hours start (in the chat timezone), null if there are no quiet hours';
+
+comment on column chats.quiet_mode is 'what happens to updates during quiet hours: `silent` (sent without a sound) or `hold` (sent when quiet hours end)';
+
+drop function if exists next_digest(varchar, int, timestamptz);
+
+-- the first `_hour` o'clock (in `_timezone`) after `_after`, daily or on mondays
+create or replace function next_digest(_delivery varchar, _hour int, _timezone varchar, _after timestamptz)
+    RETURNS timestamptz
+    LANGUAGE plpgsql
+    STABLE
+AS $$
+declare
+    _local timestamp := _after at time zone _timezone;
+    _next timestamp;
+begin
+    case _delivery
+        when 'daily' then
+            _next := date_trunc('day', _local) + make_interval(hours => _hour);
+            if _next <= _local then
+                _next := _next + interval '1 day';
+            end if;
+        when 'weekly' then
+            -- weeks start on monday
+            _next := date_trunc('week', _local) + make_interval(hours => _hour);
+            if _next <= _local then
+                _next := _next + interval '1 week';
+            end if;
+        else
+            RETURN null;
+    end case;
+
+    RETURN _next at time zone _timezone;
+end
+$$;
+
+-- the end of quiet hours if `_at` is within them, null otherwise
+create or replace function quiet_until(_start int, _end int, _timezone varchar, _at timestamptz)
+    RETURNS timestamptz
+    LANGUAGE plpgsql
+    STABLE
+AS $$
+declare
+    _local timestamp;
+    _hour int;
+    _until timestamp;
+begin
+    if _start is null or _end is null or _start = _end then
+        RETURN null;
+    end if;
+
+    _local := _at at time zone _timezone;
+    _hour := extract(hour from _local);
+
+    -- the window may wrap around midnight (e.g. from 22 to 8)
+    if (_start < _end and (_hour < _start or _hour >= _end))
+        or (_start > _end and _hour < _start and _hour >= _end) then
+        RETURN null;
+    end if;
+
+    _until := date_trunc('day', _local) + make_interval(hours => _end);
+    if _until <= _local then
+        _until := _until + interval '1 day';
+    end if;
+
+    RETURN _until at time zone _timezone;
+end
+$$;
+
+-- returns the canonical name of the timezone, or null if there is no such timezone
+create or replace function set_timezone(_user_id bigint, _timezone varchar)
+    RETURNS varchar
+    LANGUAGE plpgsql
+AS $$
+declare
+    _name varchar;
+begin
+    select tz.name into _name from pg_timezone_names as tz where lower(tz.name) = lower(_timezone) limit 1;
+    if _name is null then
+        RETURN null;
+    end if;
+
+    insert into chats (user_id, timezone) values (_user_id, _name)
+        on conflict (user_id) do update set timezone = excluded.timezone;
+
+    -- digests are rescheduled, updates held until the end of quiet hours stay as they are
+    update chats as ch
+        set next_digest = coalesce(next_digest(ch.delivery, ch.digest_hour, ch.timezone, now()), ch.next_digest)
+        where ch.user_id = _user_id;
+
+    RETURN _name;
+end
+$$;
+
+create or replace procedure set_quiet_hours(_user_id bigint, _start int, _end int, _mode varchar)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into chats (user_id, quiet_start, quiet_end, quiet_mode) values (_user_id, _start, _end, _mode)
+        on conflict (user_id) do update
+            set quiet_start = excluded.quiet_start,
+                quiet_end = excluded.quiet_end,
+                quiet_mode = excluded.quiet_mode;
+
+    -- updates held because of the previous quiet hours are sent right away
+    if _start is null and exists (select * from digest_queue as q where q.user_id = _user_id) then
+        update chats set next_digest = now() where user_id = _user_id and delivery = 'instant';
+    end if;
+end
+$$;
+
+create or replace procedure set_delivery(_user_id bigint, _delivery varchar, _hour int)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into chats (user_id, delivery, digest_hour) values (_user_id, _delivery, _hour)
+        on conflict (user_id) do update
+            set delivery = excluded.delivery,
+                digest_hour = excluded.digest_hour;
+
+    update chats as ch
+        set next_digest = next_digest(ch.delivery, ch.digest_hour, ch.timezone, now())
+        where ch.user_id = _user_id;
+
+    -- updates queued before switching to instant delivery are sent right away
+    if _delivery = 'instant' and exists (select * from digest_queue as q where q.user_id = _user_id) then
+        update chats set next_digest = now() where user_id = _user_id;
+    end if;
+end
+$$;
+
+create or replace procedure enqueue_digest(_user_id bigint, _crate varchar, _version varchar, _action varchar)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into digest_queue (user_id, crate_name, version, action)
+        values (_user_id, _crate, _version, _action);
+end
+$$;
+
+-- queues the update until the end of the current quiet hours of the chat
+create or replace procedure hold(_user_id bigint, _crate varchar, _version varchar, _action varchar)
+    LANGUAGE plpgsql
+AS $$
+begin
+    call enqueue_digest(_user_id, _crate, _version, _action);
+
+    update chats as ch
+        set next_digest = least(
+            ch.next_digest,
+            coalesce(quiet_until(ch.quiet_start, ch.quiet_end, ch.timezone, now()), now())
+        )
+        where ch.user_id = _user_id;
+end
+$$;
+
+drop function if exists due_digests();
+
+create or replace function due_digests()
+    RETURNS TABLE(user_id bigint, delivery varchar, quiet boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select ch.user_id as user_id, ch.delivery as delivery,
+            quiet_until(ch.quiet_start, ch.quiet_end, ch.timezone, now()) is not null as quiet
+        from chats as ch
+        -- digests of inactive chats wait until they are back
+        where ch.next_digest <= now() and ch.status = 'active';
+end
+$$;
+
+-- queued updates of the chat (oldest first), they are only removed from the queue by finish_digest, once the digest
+-- is in the outbox
+create or replace function queued_digest(_user_id bigint)
+    RETURNS TABLE(id bigint, crate_name varchar, version varchar, action varchar)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select q.id, q.crate_name, q.version, q.action
+        from digest_queue as q
+        where q.user_id = _user_id
+        order by q.id;
+end
+$$;
+
+create table if not exists outbox
+(
+  id bigserial not null
+    constraint outbox_pk
+      primary key,
+  chat_id bigint not null,
+  text varchar not null,
+  keyboard varchar,
+  silent boolean not null default false,
+  attempts int not null default 0,
+  not_before timestamptz not null default now(),
+  created_at timestamptz not null default now()
+);
+
+comment on table outbox is 'messages waiting to be sent, they are removed once telegram accepts them';
+
+comment on column outbox.keyboard is 'inline keyboard of the message serialized as json';
+
+comment on column outbox.not_before is 'the message is not sent before this time (e.g. after a failed attempt)';
+
+create index if not exists outbox_chat_id_index
+  on outbox (chat_id, id);
+
+-- messages are put into the outbox by finish_event and finish_digest
+drop procedure if exists push_outbox(bigint[], boolean[], varchar, varchar);
+
+drop procedure if exists finish_digest(bigint, bigint[]);
+
+-- puts the rendered digest into the outbox, removes the updates it contains from the queue and schedules the next
+-- digest, all at once so updates are neither lost nor sent twice
+create or replace procedure finish_digest(_user_id bigint, _ids bigint[], _texts varchar[], _silent boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into outbox (chat_id, silent, text)
+        select _user_id, _silent, m.text
+            from unnest(_texts) with ordinality as m(text, n)
+            order by m.n;
+
+    delete from digest_queue as q
+        where q.user_id = _user_id and q.id = any(_ids);
+
+    update chats as ch
+        set next_digest = next_digest(ch.delivery, ch.digest_hour, ch.timezone, now())
+        where ch.user_id = _user_id;
+end
+$$;
+
+-- the oldest messages which can be sent now, messages to a chat which has an earlier postponed message are
+-- held back to keep the order
+drop function if exists outbox_batch(int);
+
+create or replace function outbox_batch(_limit int)
+    RETURNS TABLE(
+        id bigint,
+        chat_id bigint,
+        text varchar,
+        keyboard varchar,
+        silent boolean,
+        attempts int,
+        post_id bigint,
+        message_id int
+    )
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select o.id, o.chat_id, o.text, o.keyboard, o.silent, o.attempts, o.post_id, p.message_id
+        from outbox as o
+            left join channel_posts as p on p.id = o.post_id
+        where o.not_before <= now()
+            and not exists (
+                select * from outbox as earlier
+                    where earlier.chat_id = o.chat_id and earlier.id < o.id and earlier.not_before > now()
+            )
+        order by o.id
+        limit _limit;
+end
+$$;
+
+create or replace procedure postpone_outbox(_id bigint, _delay_secs double precision)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update outbox
+        set attempts = attempts + 1,
+            not_before = now() + make_interval(secs => _delay_secs)
+        where id = _id;
+end
+$$;
+
+create table if not exists events
+(
+  id bigserial not null
+    constraint events_pk
+      primary key,
+  registry varchar not null,
+  krate varchar not null,
+  action varchar not null,
+  prev varchar,
+  created_at timestamptz not null default now()
+);
+
+comment on table events is 'updates found in indexes which are yet to be turned into notifications';
+
+comment on column events.krate is 'index entry of the crate version (json)';
+
+comment on column events.prev is 'index entry of the version preceding `krate` (json), only known for new versions';
+
+-- records updates found in the index and moves its cursor at once. The cursor is either the commit of a git index
+-- (`_cursor`) or the state of a crate file of a sparse index (`_file`, the state is removed if `_file_state` is null)
+create or replace procedure record_events(
+    _registry varchar,
+    _cursor varchar(40),
+    _file varchar,
+    _file_state varchar,
+    _krates varchar[],
+    _actions varchar[],
+    _prevs varchar[]
+)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into events (registry, krate, action, prev)
+        select _registry, e.krate, e.action, e.prev
+            from unnest(_krates, _actions, _prevs) with ordinality as e(krate, action, prev, n)
+            order by e.n;
+
+    if _cursor is not null then
+        call set_cursor(_registry, _cursor);
+    end if;
+
+    if _file is not null and _file_state is null then
+        delete from sparse_files where registry = _registry and crate_name = _file;
+    elsif _file is not null then
+        insert into sparse_files (registry, crate_name, state) values (_registry, _file, _file_state)
+            on conflict (registry, crate_name) do update set state = excluded.state;
+    end if;
+end
+$$;
+
+create table if not exists channel_posts
+(
+  id bigserial not null
+    constraint channel_posts_pk
+      primary key,
+  chat_id bigint not null,
+  group_key varchar not null,
+  message_id int,
+  lines varchar[] not null,
+  opened_at timestamptz not null default now()
+);
+
+comment on table channel_posts is 'channel posts which collect several updates, they are edited while their window is open';
+
+comment on column channel_posts.group_key is 'updates with the same key are collected into the same post (e.g. crate name prefix)';
+
+comment on column channel_posts.message_id is 'id of the sent message, null if the post is not sent yet';
+
+comment on column channel_posts.lines is 'html lines of the post, one per update';
+
+create index if not exists channel_posts_chat_id_index
+  on channel_posts (chat_id, group_key, opened_at);
+
+alter table outbox
+  add column if not exists post_id bigint
+    constraint outbox_channel_posts_id_fk
+      references channel_posts
+        on delete cascade;
+
+comment on column outbox.post_id is 'channel post the message is (a new version of), it''s edited if it was already sent';
+
+-- the post which collects updates of `_group_key` in the chat, if its window is still open. Posts whose window is
+-- closed are removed (once they are sent)
+create or replace function open_post(_chat_id bigint, _group_key varchar, _window_secs double precision)
+    RETURNS TABLE(id bigint, lines varchar[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from channel_posts as p
+        where p.opened_at < now() - make_interval(secs => _window_secs)
+            and not exists (select * from outbox as o where o.post_id = p.id);
+
+    RETURN QUERY select p.id, p.lines
+        from channel_posts as p
+        where p.chat_id = _chat_id
+            and p.group_key = _group_key
+            and p.opened_at >= now() - make_interval(secs => _window_secs)
+        order by p.id desc
+        limit 1;
+end
+$$;
+
+create or replace procedure set_post_message(_post_id bigint, _message_id int)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update channel_posts set message_id = _message_id where id = _post_id;
+end
+$$;
+
+drop procedure if exists finish_event(
+    bigint, varchar, varchar, varchar, varchar[], varchar[], int[], bigint[], boolean[], bigint[], bigint[], boolean
+);
+
+-- queues everything the event turned into and removes it, so that an event is never delivered twice
+create or replace procedure finish_event(
+    _event_id bigint,
+    _crate varchar,
+    _version varchar,
+    _action varchar,
+    _texts varchar[],
+    _keyboards varchar[],
+    _message_idx int[],
+    _chat_ids bigint[],
+    _silent boolean[],
+    _digest_chats bigint[],
+    _held_chats bigint[],
+    _delete_crate boolean,
+    _post_chat_id bigint,
+    _post_id bigint,
+    _post_group_key varchar,
+    _post_lines varchar[],
+    _post_text varchar
+)
+    LANGUAGE plpgsql
+AS $$
+declare
+    _chat_id bigint;
+begin
+    -- the update is added to a channel post (`_post_id` is null if a new one is started), only the latest version of
+    -- the post is kept in the outbox
+    if _post_chat_id is not null then
+        if _post_id is null then
+            insert into channel_posts (chat_id, group_key, lines)
+                values (_post_chat_id, _post_group_key, _post_lines)
+                returning id into _post_id;
+        else
+            update channel_posts set lines = _post_lines where id = _post_id;
+            delete from outbox where post_id = _post_id;
+        end if;
+
+        insert into outbox (chat_id, silent, text, post_id)
+            values (_post_chat_id, true, _post_text, _post_id);
+    end if;
+
+    -- `_message_idx` are indexes in `_texts` and `_keyboards` (starting from 1)
+    insert into outbox (chat_id, silent, text, keyboard)
+        select m.chat_id, m.silent, _texts[m.idx], _keyboards[m.idx]
+            from unnest(_message_idx, _chat_ids, _silent) with ordinality as m(idx, chat_id, silent, n)
+            order by m.n;
+
+    foreach _chat_id in array _digest_chats loop
+        call enqueue_digest(_chat_id, _crate, _version, _action);
+    end loop;
+
+    foreach _chat_id in array _held_chats loop
+        call hold(_chat_id, _crate, _version, _action);
+    end loop;
+
+    if _delete_crate then
+        call delete_crate(_crate);
+    end if;
+
+    delete from events where id = _event_id;
+end
+$$;
+
+alter table chats
+  add column if not exists status varchar not null default 'active';
+
+comment on column chats.status is 'whether the bot can send messages to the chat: `active`, `blocked` (by the user), `kicked` (from the group) or `migrated` (to a supergroup)';
+
+-- returns the previous status of the chat. Messages waiting in the outbox are dropped if the chat becomes inactive
+create or replace function set_chat_status(_user_id bigint, _status varchar)
+    RETURNS varchar
+    LANGUAGE plpgsql
+AS $$
+declare
+    _old varchar;
+begin
+    select ch.status into _old from chats as ch where ch.user_id = _user_id;
+
+    insert into chats (user_id, status) values (_user_id, _status)
+        on conflict (user_id) do update
+            set status = excluded.status;
+
+    if _status <> 'active' then
+        delete from outbox where chat_id = _user_id;
+    end if;
+
+    RETURN coalesce(_old, 'active');
+end
+$$;
+
+-- moves subscriptions, settings and pending messages of a group which was upgraded to a supergroup to its new id
+create or replace procedure migrate_chat(_from bigint, _to bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update subscriptions as s
+        set user_id = _to
+        where s.user_id = _from
+            and not exists (select * from subscriptions as t where t.user_id = _to and t.crate_id = s.crate_id);
+    delete from subscriptions where user_id = _from;
+
+    update watched_versions as w
+        set user_id = _to
+        where w.user_id = _from
+            and not exists (
+                select * from watched_versions as t
+                    where t.user_id = _to and t.crate_id = w.crate_id and t.version = w.version
+            );
+    delete from watched_versions where user_id = _from;
+
+    update digest_queue set user_id = _to where user_id = _from;
+    update outbox set chat_id = _to where chat_id = _from;
+
+    update chats as ch
+        set user_id = _to, status = 'active'
+        where ch.user_id = _from and not exists (select * from chats as t where t.user_id = _to);
+
+    insert into chats (user_id, status) values (_from, 'migrated')
+        on conflict (user_id) do update
+            set status = excluded.status;
 end
 $$;
@@ -1,39 +1,105 @@
-use std::{fmt::Debug, ops::Not, path::PathBuf, sync::Arc};
+use std::{collections::HashSet, fmt::Debug, ops::Not, sync::Arc};
 
-use fntools::value::ValueExt;
+use semver::{Version, VersionReq};
 use teloxide::{
     dispatching::UpdateFilterExt,
     dptree::deps,
+    net::Download,
     prelude::{Requester, *},
-    utils::command::{BotCommands, ParseError},
-    RequestError,
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle,
+        InputMessageContent, InputMessageContentText, ParseMode,
+    },
+    utils::{
+        command::{BotCommands, ParseError},
+        html,
+    },
+    DownloadError, RequestError,
 };
 
-use crate::{cfg::Config, db::Database, krate::Crate, util::crate_path, Bot, VERSION};
+use crate::{
+    cfg::{Config, Registry},
+    db::{
+        ChatStatus, Database, Delivery, Filter, LockfileChanges, QuietHours, QuietMode, Settings,
+        Subscription, DEFAULT_DIGEST_HOUR,
+    },
+    index::ActionKind,
+    krate::Crate,
+    lockfile,
+    names::Names,
+    util::normalize_name,
+    Bot, VERSION,
+};
 
 type OptString = Option<String>;
 
+/// Maximum number of crates returned for an inline query.
+const MAX_INLINE_RESULTS: usize = 10;
+
+/// For how long (in seconds) telegram may cache results of an inline query.
+const INLINE_CACHE_TIME: u32 = 60;
+
+// Actions of callback buttons, callback data is `action:crate`
+const SUBSCRIBE: &str = "sub";
+const UNSUBSCRIBE: &str = "unsub";
+const MUTE: &str = "mute";
+/// Argument is `crate#version`
+const DEPS: &str = "deps";
+/// Argument is `page:sort`
+const LIST: &str = "list";
+/// Argument is `page:sort:crate`
+const LIST_UNSUBSCRIBE: &str = "lunsub";
+
+/// Number of subscriptions on a page of `/list`.
+const LIST_PAGE_SIZE: usize = 10;
+
+/// For how many days "Mute" button mutes updates of a crate.
+const MUTE_DAYS: i32 = 7;
+
+/// Maximum size of uploaded `Cargo.lock`, in bytes.
+const MAX_LOCKFILE_SIZE: u32 = 5 * 1024 * 1024;
+
 #[derive(BotCommands, Clone, PartialEq, Eq, Debug)]
 #[command(rename_rule = "lowercase", parse_with = "split")]
 enum Command {
     Start,
+    #[command(parse_with = crate_list)]
+    Subscribe(Vec<(String, OptString)>),
+    #[command(parse_with = names)]
+    Unsubscribe(Vec<String>),
     #[command(parse_with = opt)]
-    Subscribe(OptString),
-    #[command(parse_with = opt)]
-    Unsubscribe(OptString),
+    Unmute(OptString),
     List,
+    #[command(parse_with = crate_args)]
+    Filter(OptString, OptString),
+    #[command(parse_with = crate_args)]
+    Watch(OptString, OptString),
+    #[command(parse_with = crate_args)]
+    Unwatch(OptString, OptString),
+    #[command(parse_with = crate_args)]
+    Digest(OptString, OptString),
+    #[command(parse_with = opt)]
+    Timezone(OptString),
+    #[command(parse_with = crate_args)]
+    Quiet(OptString, OptString),
 }
 
 #[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
 enum HErr {
     Tg(RequestError),
+    Download(DownloadError),
     Bd(tokio_postgres::Error),
     GetUser,
     NotAdmin,
 }
 
 pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>) {
-    let commands = |bot: Bot, msg: Message, cmd: Command, db: Database, cfg: Arc<Config>| async move {
+    let commands = |bot: Bot,
+                    msg: Message,
+                    cmd: Command,
+                    db: Database,
+                    cfg: Arc<Config>,
+                    names: Names| async move {
         let chat_id = msg.chat.id;
 
         check_privileges(&bot, &msg).await?;
@@ -53,84 +119,590 @@ pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>) {
                 );
                 bot.send_message(chat_id, greeting).await?;
             }
-            Command::Subscribe(Some(krate)) => match subscribe(chat_id, &krate, &db, &cfg).await? {
-                Some(ver) => {
+            Command::Subscribe(crates) if crates.is_empty() => {
+                bot.send_message(
+                    chat_id,
+                    "You need to specify the crate you want to subscribe. Like this: \
+                     <pre>/subscribe serde</pre>\n\
+                     You can also specify a version requirement, to only get updates of \
+                     matching versions: <pre>/subscribe serde ^1</pre>\n\
+                     Several crates can be subscribed at once: \
+                     <pre>/subscribe serde ^1, tokio, log</pre>\n\
+                     Crates from other registries are specified with the registry name: \
+                     <pre>/subscribe registry:crate</pre>\n\
+                     To subscribe to all dependencies of your project, send its \
+                     <code>Cargo.lock</code> file.",
+                )
+                .await?;
+            }
+            Command::Subscribe(crates) => {
+                let subscribed = subscribed(chat_id, &db).await?;
+
+                let mut summary = Summary::default();
+                let mut single = None;
+                for (krate, req) in &crates {
+                    let req = match req.as_deref().map(str::parse::<VersionReq>).transpose() {
+                        Ok(req) => req,
+                        Err(err) => {
+                            summary.invalid.push(format!(
+                                "<code>{}</code> (<code>{}</code>: {})",
+                                html::escape(krate),
+                                html::escape(req.as_deref().unwrap_or_default()),
+                                html::escape(&err.to_string()),
+                            ));
+                            continue;
+                        }
+                    };
+
+                    // Resubscribing only makes sense to change the requirement
+                    if req.is_none() && subscribed.contains(&normalized(krate, &cfg)) {
+                        summary
+                            .already
+                            .push(format!("<code>{}</code>", html::escape(krate)));
+                        continue;
+                    }
+
+                    match subscribe(chat_id, krate, req.as_ref(), &db, &cfg).await? {
+                        Some((krate, ver)) => {
+                            let only = match &req {
+                                Some(req) => format!(
+                                    " (only versions matching <code>{}</code>)",
+                                    html::escape(&req.to_string())
+                                ),
+                                None => String::new(),
+                            };
+
+                            // The current version and links are only shown when subscribing
+                            // to a single crate, to keep the message short
+                            if crates.len() == 1 {
+                                single = Some(format!(
+                                    "You've successfully subscribed for updates on \
+                                     <code>{krate}</code>{ver} crate{only}. Use /unsubscribe to \
+                                     unsubscribe."
+                                ));
+                            }
+
+                            summary.done.push(format!("<code>{krate}</code>{only}"));
+                        }
+                        None => {
+                            let suggestions = names.suggest(krate, &cfg).await;
+                            let hint = match &suggestions[..] {
+                                [] => String::new(),
+                                suggestions => format!(
+                                    " (did you mean <code>{}</code>?)",
+                                    suggestions.join("</code>, <code>")
+                                ),
+                            };
+
+                            summary
+                                .missing
+                                .push(format!("<code>{}</code>{hint}", html::escape(krate)));
+                        }
+                    }
+                }
+
+                let message = single
+                    .unwrap_or_else(|| summary.message("Subscribed to", "Already subscribed to"));
+
+                bot.send_message(chat_id, message)
+                    .disable_web_page_preview(true)
+                    .await?;
+            }
+            Command::Unsubscribe(crates) if crates.is_empty() => {
+                bot.send_message(
+                    chat_id,
+                    "You need to specify the crate you want to unsubscribe. Like this: \
+                     <code>/unsubscribe serde</code> (or several crates: \
+                     <code>/unsubscribe serde tokio</code>)",
+                )
+                .await?;
+            }
+            Command::Unsubscribe(crates) => {
+                let subscribed = subscribed(chat_id, &db).await?;
+
+                let mut summary = Summary::default();
+                for krate in &crates {
+                    let code = format!("<code>{}</code>", html::escape(krate));
+                    if subscribed.contains(&normalized(krate, &cfg)) {
+                        db.unsubscribe(chat_id, &qualified(krate, &cfg)).await?;
+                        summary.done.push(code);
+                    } else {
+                        summary.already.push(code);
+                    }
+                }
+
+                let message = match &crates[..] {
+                    [krate] if summary.done.len() == 1 => format!(
+                        "You've successfully unsubscribed for updates on <code>{}</code> crate. \
+                         Use /subscribe to subscribe back.",
+                        html::escape(krate)
+                    ),
+                    _ => summary.message("Unsubscribed from", "You weren't subscribed to"),
+                };
+
+                bot.send_message(chat_id, message).await?;
+            }
+            Command::List => {
+                let (text, keyboard) = list_page(chat_id, 0, Sort::Name, &db, &cfg).await?;
+
+                let request = bot
+                    .send_message(chat_id, text)
+                    .disable_web_page_preview(true);
+                match keyboard {
+                    Some(keyboard) => request.reply_markup(keyboard).await?,
+                    None => request.await?,
+                };
+            }
+            Command::Filter(Some(krate), Some(kinds)) => {
+                let filter = match kinds.parse::<Filter>() {
+                    Ok(filter) => filter,
+                    Err(err) => {
+                        bot.send_message(chat_id, format!("Error: {}.", html::escape(&err)))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                if db
+                    .set_filter(chat_id, &qualified(&krate, &cfg), filter)
+                    .await?
+                {
                     bot.send_message(
                         chat_id,
                         format!(
-                            "You've successfully subscribed for updates on \
-                             <code>{krate}</code>{ver} crate. Use /unsubscribe to unsubscribe."
+                            "From now on you'll be notified only about <code>{filter}</code> \
+                             updates of <code>{}</code> crate.",
+                            html::escape(&krate)
+                        ),
+                    )
+                    .await?;
+                } else {
+                    bot.send_message(
+                        chat_id,
+                        format!(
+                            "Error: you aren't subscribed to <code>{}</code> crate. Use \
+                             /subscribe to subscribe to it first.",
+                            html::escape(&krate)
                         ),
                     )
-                    .disable_web_page_preview(true)
                     .await?;
                 }
-                None => {
+            }
+            Command::Filter(..) => {
+                bot.send_message(
+                    chat_id,
+                    "You need to specify the crate and kinds of updates you want to be notified \
+                     about (<code>new</code>, <code>yanked</code> (this includes version \
+                     removals), <code>unyanked</code> and <code>pre</code> for pre-releases, or \
+                     <code>all</code>). Like this: <pre>/filter serde new yanked</pre>",
+                )
+                .await?;
+            }
+            Command::Watch(Some(krate), Some(version)) => {
+                let found = match cfg.resolve(&krate) {
+                    Some((registry, name)) => Crate::find(name, registry)
+                        .await
+                        .ok()
+                        .map(|found| registry.qualify(&found.id.name)),
+                    None => None,
+                };
+
+                if let Some(krate) = found {
+                    match version.parse::<Version>() {
+                        Ok(_) => {
+                            db.watch(chat_id, &krate, &version).await?;
+                            bot.send_message(
+                                chat_id,
+                                format!(
+                                    "You'll be alerted if <code>{krate}#{version}</code> is \
+                                     yanked. Use /unwatch to stop watching it."
+                                ),
+                            )
+                            .await?;
+                        }
+                        Err(err) => {
+                            bot.send_message(
+                                chat_id,
+                                format!(
+                                    "Error: invalid version <code>{}</code>: {}.",
+                                    html::escape(&version),
+                                    html::escape(&err.to_string())
+                                ),
+                            )
+                            .await?;
+                        }
+                    }
+                } else {
                     bot.send_message(
                         chat_id,
-                        format!("Error: there is no such crate <code>{krate}</code>."),
+                        format!(
+                            "Error: there is no such crate <code>{}</code>.",
+                            html::escape(&krate)
+                        ),
                     )
                     .await?;
                 }
-            },
+            }
+            Command::Unwatch(Some(krate), Some(version)) => {
+                let watched = db
+                    .unwatch(chat_id, &qualified(&krate, &cfg), &version)
+                    .await?;
+
+                let code = html::escape(&format!("{krate}#{version}"));
+                let text = if watched {
+                    format!("You've stopped watching <code>{code}</code>.")
+                } else {
+                    format!("Error: you aren't watching <code>{code}</code>.")
+                };
 
-            Command::Subscribe(None) => {
+                bot.send_message(chat_id, text).await?;
+            }
+            Command::Unmute(Some(krate)) => {
+                let text = if db.unmute(chat_id, &qualified(&krate, &cfg)).await? {
+                    format!(
+                        "Updates of <code>{}</code> are unmuted.",
+                        html::escape(&krate)
+                    )
+                } else {
+                    format!(
+                        "Error: you aren't subscribed to <code>{}</code>.",
+                        html::escape(&krate)
+                    )
+                };
+
+                bot.send_message(chat_id, text).await?;
+            }
+            Command::Unmute(None) => {
                 bot.send_message(
                     chat_id,
-                    "You need to specify the crate you want to subscribe. Like this: \
-                     <pre>/subscribe serde</pre>",
+                    "You need to specify the crate you want to unmute. Like this: \
+                     <pre>/unmute serde</pre>",
+                )
+                .await?;
+            }
+            Command::Watch(..) | Command::Unwatch(..) => {
+                bot.send_message(
+                    chat_id,
+                    "You need to specify the crate and its exact version. Like this: \
+                     <pre>/watch serde 1.0.152</pre>\n\
+                     Versions from an uploaded <code>Cargo.lock</code> are watched automatically.",
                 )
                 .await?;
             }
+            Command::Digest(Some(delivery), hour) => {
+                let delivery = match delivery.parse::<Delivery>() {
+                    Ok(delivery) => delivery,
+                    Err(err) => {
+                        bot.send_message(chat_id, format!("Error: {}.", html::escape(&err)))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                let hour = match hour.as_deref().map(parse_hour) {
+                    None => DEFAULT_DIGEST_HOUR,
+                    Some(Some(hour)) => hour,
+                    Some(None) => {
+                        bot.send_message(chat_id, "Error: the hour must be a number from 0 to 23.")
+                            .await?;
+                        return Ok(());
+                    }
+                };
 
-            Command::Unsubscribe(Some(krate)) => {
-                db.unsubscribe(chat_id, &krate).await?;
+                db.set_delivery(chat_id, delivery, hour).await?;
+                let settings = db.settings(chat_id).await?;
                 bot.send_message(
                     chat_id,
                     format!(
-                        "You've successfully unsubscribed for updates on <code>{krate}</code> \
-                         crate. Use /subscribe to subscribe back."
+                        "From now on updates will be {}.",
+                        describe_delivery(&settings)
                     ),
                 )
                 .await?;
             }
-            Command::Unsubscribe(None) => {
+            Command::Digest(None, _) => {
+                let settings = db.settings(chat_id).await?;
                 bot.send_message(
                     chat_id,
-                    "You need to specify the crate you want to unsubscribe. Like this: \
-                     <code>/unsubscribe serde</code>",
+                    format!(
+                        "Currently updates are {}.\n\
+                         \n\
+                         To get all updates of a day (or a week) in a single message, specify \
+                         the hour (in your /timezone) at which it should be sent. Like this: \
+                         <pre>/digest daily 9</pre>\
+                         Weekly digests are sent on mondays: <pre>/digest weekly 18</pre>\
+                         Use <code>/digest off</code> to get every update right away again. \
+                         Alerts about watched versions are never delayed.",
+                        describe_delivery(&settings)
+                    ),
                 )
                 .await?;
             }
-            Command::List => {
-                let subscriptions = list(chat_id, &db, &cfg).await?;
-
-                if subscriptions.is_empty() {
+            Command::Timezone(Some(timezone)) => match db.set_timezone(chat_id, &timezone).await? {
+                Some(timezone) => {
                     bot.send_message(
                         chat_id,
-                        String::from(
-                            "Currently you aren't subscribed to anything. Use /subscribe to \
-                             subscribe to some crate.",
+                        format!(
+                            "Your timezone is now <code>{timezone}</code>, it's used for quiet \
+                             hours and digests."
                         ),
                     )
                     .await?;
-                } else {
+                }
+                None => {
                     bot.send_message(
                         chat_id,
                         format!(
-                            "You are currently subscribed to:\n— <code>{}",
-                            subscriptions.join("\n— <code>")
+                            "Error: unknown timezone <code>{}</code>. Use names from the tz \
+                             database, like <code>Europe/Berlin</code> or <code>UTC</code>.",
+                            html::escape(&timezone)
                         ),
                     )
-                    .disable_web_page_preview(true)
                     .await?;
                 }
+            },
+            Command::Timezone(None) => {
+                let settings = db.settings(chat_id).await?;
+                bot.send_message(
+                    chat_id,
+                    format!(
+                        "Your timezone is <code>{}</code>, it's used for quiet hours and \
+                         digests. To change it, specify the name of your timezone. Like this: \
+                         <pre>/timezone Europe/Berlin</pre>",
+                        settings.timezone
+                    ),
+                )
+                .await?;
+            }
+            Command::Quiet(Some(off), None) if off == "off" => {
+                db.set_quiet_hours(chat_id, None).await?;
+                bot.send_message(chat_id, "Quiet hours are turned off.")
+                    .await?;
+            }
+            Command::Quiet(Some(start), Some(rest)) => {
+                let quiet = match parse_quiet(&start, &rest) {
+                    Ok(quiet) => quiet,
+                    Err(err) => {
+                        bot.send_message(chat_id, format!("Error: {err}.")).await?;
+                        return Ok(());
+                    }
+                };
+
+                db.set_quiet_hours(chat_id, Some(quiet)).await?;
+                let settings = db.settings(chat_id).await?;
+                bot.send_message(
+                    chat_id,
+                    format!("From now on {}.", describe_quiet_hours(&settings)),
+                )
+                .await?;
+            }
+            Command::Quiet(..) => {
+                let settings = db.settings(chat_id).await?;
+                bot.send_message(
+                    chat_id,
+                    format!(
+                        "Currently {}.\n\
+                         \n\
+                         To not be disturbed at night, specify when quiet hours start and end \
+                         (in your /timezone). Like this: <pre>/quiet 22 8</pre>\
+                         During quiet hours notifications are sent silently, add \
+                         <code>hold</code> to get them when quiet hours end instead: \
+                         <pre>/quiet 22 8 hold</pre>\
+                         Use <code>/quiet off</code> to turn quiet hours off.",
+                        describe_quiet_hours(&settings)
+                    ),
+                )
+                .await?;
             }
         }
 
         Ok::<_, HErr>(())
     };
 
+    let lockfile = |bot: Bot, msg: Message, db: Database, cfg: Arc<Config>| async move {
+        let chat_id = msg.chat.id;
+
+        check_privileges(&bot, &msg).await?;
+
+        // Checked by the filter below
+        let document = msg.document().expect("lockfile is a document");
+        if document.file.size > MAX_LOCKFILE_SIZE {
+            bot.send_message(chat_id, "Error: the lockfile is too big.")
+                .await?;
+            return Ok(());
+        }
+
+        let file = bot.get_file(&document.file.id).await?;
+        let mut lockfile = Vec::new();
+        bot.download_file(&file.path, &mut lockfile).await?;
+
+        let locked = String::from_utf8(lockfile)
+            .map_err(|err| err.to_string())
+            .and_then(|lockfile| lockfile::parse(&lockfile, &cfg).map_err(|err| err.to_string()));
+        let locked = match locked {
+            Ok(locked) => locked,
+            Err(err) => {
+                bot.send_message(
+                    chat_id,
+                    format!("Error: couldn't parse the lockfile: {}", html::escape(&err)),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        let LockfileChanges {
+            added,
+            updated,
+            removed,
+        } = db
+            .replace_lockfile(chat_id, &locked.crates, &locked.versions)
+            .await?;
+
+        let skipped = match locked.skipped {
+            0 => String::new(),
+            n => format!(" {n} packages were skipped as they are not from a known registry."),
+        };
+
+        bot.send_message(
+            chat_id,
+            format!(
+                "You are now subscribed to {} crates from your <code>Cargo.lock</code> ({added} \
+                 added, {updated} updated, {removed} removed). You'll be notified about new \
+                 compatible versions, and alerted if any of the locked versions is \
+                 yanked.{skipped}\n\
+                 \n\
+                 Upload a new <code>Cargo.lock</code> to replace these subscriptions, your other \
+                 subscriptions are left as is.",
+                locked.crates.len()
+            ),
+        )
+        .await?;
+
+        Ok::<_, HErr>(())
+    };
+
+    let inline = |bot: Bot, query: InlineQuery, cfg: Arc<Config>, names: Names| async move {
+        let mut found = names
+            .search(query.query.trim(), &cfg, MAX_INLINE_RESULTS)
+            .await;
+
+        // Names are only known for git indexes, try the exact name otherwise
+        if found.is_empty() && !query.query.trim().is_empty() {
+            found.push(query.query.trim().to_owned());
+        }
+
+        let mut results = Vec::new();
+        for krate in found {
+            if let Some(result) = crate_card(&krate, results.len(), &cfg).await {
+                results.push(result);
+            }
+        }
+
+        bot.answer_inline_query(query.id, results)
+            .cache_time(INLINE_CACHE_TIME)
+            .await?;
+
+        Ok::<_, HErr>(())
+    };
+
+    let callback = |bot: Bot, query: CallbackQuery, db: Database, cfg: Arc<Config>| async move {
+        let data = query.data.as_deref().unwrap_or_default();
+        let user_id = query.from.id;
+
+        // Answers are plain text, not html
+        let text = match (data.split_once(':'), &query.message) {
+            // Existing subscriptions are left as they are (they may have a requirement, be
+            // muted or come from a lockfile)
+            (Some((SUBSCRIBE, krate)), _)
+                if subscribed(user_id.into(), &db)
+                    .await?
+                    .contains(&normalized(krate, &cfg)) =>
+            {
+                Some(format!("You're already subscribed to {krate}."))
+            }
+            (Some((SUBSCRIBE, krate)), _) => {
+                match subscribe(user_id.into(), krate, None, &db, &cfg).await? {
+                    Some((krate, _)) => Some(format!(
+                        "You've subscribed to {krate}. Updates are sent to the private chat with \
+                         the bot, start it if you haven't yet."
+                    )),
+                    None => Some(format!("Error: there is no such crate {krate}.")),
+                }
+            }
+            // Buttons of notifications and `/list`
+            (Some((UNSUBSCRIBE | MUTE | LIST_UNSUBSCRIBE, _)), Some(msg))
+                if !msg.chat.is_private() && !is_admin(&bot, msg.chat.id, user_id).await? =>
+            {
+                Some(String::from("Error: only admins can do that."))
+            }
+            (Some((UNSUBSCRIBE, krate)), Some(msg)) => {
+                db.unsubscribe(msg.chat.id, krate).await?;
+                Some(format!(
+                    "You've unsubscribed from {krate}. Use /subscribe to subscribe back."
+                ))
+            }
+            (Some((MUTE, krate)), Some(msg)) => {
+                if db.mute(msg.chat.id, krate, MUTE_DAYS).await? {
+                    Some(format!(
+                        "Updates of {krate} are muted for {MUTE_DAYS} days. Use /unmute {krate} \
+                         to unmute them earlier."
+                    ))
+                } else {
+                    Some(format!("Error: you aren't subscribed to {krate}."))
+                }
+            }
+            (Some((DEPS, krate)), Some(msg)) => {
+                let text = match krate.split_once('#') {
+                    Some((krate, vers)) => deps_diff(krate, vers, &cfg).await,
+                    None => String::from("Error: unknown version."),
+                };
+
+                bot.send_message(msg.chat.id, text)
+                    .reply_to_message_id(msg.id)
+                    .disable_web_page_preview(true)
+                    .await?;
+                None
+            }
+            (Some((LIST, arg)), Some(msg)) => match arg.split_once(':') {
+                Some((page, sort)) => {
+                    let page = page.parse().unwrap_or(0);
+                    let sort = Sort::parse(sort).unwrap_or(Sort::Name);
+                    edit_list(&bot, msg, page, sort, &db, &cfg).await?;
+                    None
+                }
+                None => Some(String::from("Error: unknown page.")),
+            },
+            (Some((LIST_UNSUBSCRIBE, arg)), Some(msg)) => {
+                let mut parts = arg.splitn(3, ':');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(page), Some(sort), Some(krate)) => {
+                        db.unsubscribe(msg.chat.id, krate).await?;
+
+                        let page = page.parse().unwrap_or(0);
+                        let sort = Sort::parse(sort).unwrap_or(Sort::Name);
+                        edit_list(&bot, msg, page, sort, &db, &cfg).await?;
+
+                        Some(format!("You've unsubscribed from {krate}."))
+                    }
+                    _ => Some(String::from("Error: unknown crate.")),
+                }
+            }
+            _ => {
+                log::warn!("Unknown callback data: {data:?}");
+                Some(String::from("Error: unknown action."))
+            }
+        };
+
+        let answer = bot.answer_callback_query(query.id);
+        match text {
+            Some(text) => answer.text(text).await?,
+            None => answer.await?,
+        };
+
+        Ok::<_, HErr>(())
+    };
+
     let unblock = |bot: Bot, update: ChatMemberUpdated, db: Database| async move {
         let ChatMemberUpdated {
             chat,
@@ -139,20 +711,23 @@ pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>) {
             ..
         } = &update;
         if old_chat_member.is_present() && !new_chat_member.is_present() {
-            // FIXME: ideally the bot should just mark the user as temporary unavailable
-            // (that is: until unblock/restart), but I'm too lazy to implement it rn.
-            for sub in db.list_subscriptions(chat.id).await? {
-                db.unsubscribe(chat.id, &sub).await?;
-            }
+            // Subscriptions are kept (but not notified), so that they work again once the bot
+            // is back
+            let status = if chat.is_private() {
+                ChatStatus::Blocked
+            } else {
+                ChatStatus::Kicked
+            };
+            db.set_chat_status(chat.id, status).await?;
         } else if !old_chat_member.is_present() && new_chat_member.is_present() {
-            // Do not trigger when the bot is added to a group
-            //
-            // FIXME: when we'll store bot bannedness in DB, this should check that the bot
-            // was previously blocked instead
-            if chat.is_private() {
+            let old = db.set_chat_status(chat.id, ChatStatus::Active).await?;
+
+            // Do not trigger when the bot is added to a new chat
+            let subscriptions = db.list_subscriptions(chat.id).await?.count();
+            if old != ChatStatus::Active && subscriptions > 0 {
                 bot.send_message(
                     chat.id,
-                    "You have previously blocked this bot. This removed all your subscriptions.",
+                    format!("Welcome back! Your {subscriptions} subscriptions are restored."),
                 )
                 .await?;
             }
@@ -163,16 +738,40 @@ pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>) {
         Ok::<_, HErr>(())
     };
 
+    // The group was upgraded to a supergroup, which has another id
+    let migrate = |msg: Message, db: Database| async move {
+        if let Some(to) = msg.migrate_to_chat_id() {
+            db.migrate_chat(msg.chat.id, to).await?;
+        }
+
+        Ok::<_, HErr>(())
+    };
+
     let handler = dptree::entry()
         .branch(
             Update::filter_message()
                 .filter_command::<Command>()
                 .endpoint(commands),
         )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message| {
+                    let name = msg.document().and_then(|d| d.file_name.as_deref());
+                    name == Some("Cargo.lock")
+                })
+                .endpoint(lockfile),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message| msg.migrate_to_chat_id().is_some())
+                .endpoint(migrate),
+        )
+        .branch(Update::filter_inline_query().endpoint(inline))
+        .branch(Update::filter_callback_query().endpoint(callback))
         .branch(Update::filter_my_chat_member().endpoint(unblock));
 
     Dispatcher::builder(bot, handler)
-        .dependencies(deps![db, cfg])
+        .dependencies(deps![db, cfg, Names::default()])
         .default_handler(|_| async {})
         .enable_ctrlc_handler()
         .build()
@@ -180,37 +779,265 @@ pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>) {
         .await;
 }
 
-async fn list(chat_id: ChatId, db: &Database, cfg: &Config) -> Result<Vec<String>, HErr> {
+/// Order of subscriptions in `/list`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    Name,
+    /// The most recently released crates first
+    Released,
+}
+
+impl Sort {
+    fn as_str(self) -> &'static str {
+        match self {
+            Sort::Name => "name",
+            Sort::Released => "date",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "name" => Some(Sort::Name),
+            "date" => Some(Sort::Released),
+            _ => None,
+        }
+    }
+}
+
+/// Renders page `page` of the chat subscriptions (`/list`), returns its text
+/// and keyboard (navigation and unsubscribe buttons).
+async fn list_page(
+    chat_id: ChatId,
+    page: usize,
+    sort: Sort,
+    db: &Database,
+    cfg: &Config,
+) -> Result<(String, Option<InlineKeyboardMarkup>), HErr> {
     let mut subscriptions: Vec<_> = db.list_subscriptions(chat_id).await?.collect();
-    for sub in &mut subscriptions {
-        match Crate::read_last(sub, cfg).await {
-            Ok(krate) => {
-                sub.push('#');
-                sub.push_str(&krate.id.vers);
-                sub.push_str("</code> ");
-                sub.push_str(&krate.html_links());
-            }
-            Err(_) => {
-                sub.push_str(" </code>");
-                /* silently ignore error & just don't add links */
+
+    if subscriptions.is_empty() {
+        let text = "Currently you aren't subscribed to anything. Use /subscribe to subscribe to \
+                    some crate.";
+        return Ok((String::from(text), None));
+    }
+
+    // `subscriptions` is not empty
+    let last_page = (subscriptions.len() - 1) / LIST_PAGE_SIZE;
+    let page = page.min(last_page);
+
+    // Only crates on the page are read from the index, unless all of them are
+    // needed for sorting
+    let entries = match sort {
+        Sort::Name => {
+            subscriptions.sort_by(|a, b| a.krate.cmp(&b.krate));
+
+            let mut entries = Vec::new();
+            for sub in subscriptions
+                .into_iter()
+                .skip(page * LIST_PAGE_SIZE)
+                .take(LIST_PAGE_SIZE)
+            {
+                let latest = latest(&sub.krate, cfg).await;
+                entries.push((sub, latest));
             }
+
+            entries
+        }
+        Sort::Released => {
+            let mut entries = Vec::new();
+            for sub in subscriptions {
+                let latest = latest(&sub.krate, cfg).await;
+                entries.push((sub, latest));
+            }
+
+            // `pubtime`s are in the same format, so they are compared
+            // chronologically, crates without it go last
+            let pubtime = |latest: &Option<(Crate, &Registry)>| {
+                latest.as_ref().and_then(|(krate, _)| krate.pubtime.clone())
+            };
+            entries.sort_by(|(a, a_latest), (b, b_latest)| {
+                pubtime(b_latest)
+                    .cmp(&pubtime(a_latest))
+                    .then_with(|| a.krate.cmp(&b.krate))
+            });
+
+            entries
+                .into_iter()
+                .skip(page * LIST_PAGE_SIZE)
+                .take(LIST_PAGE_SIZE)
+                .collect()
+        }
+    };
+
+    let mut lines = Vec::new();
+    let mut rows = Vec::new();
+    for (sub, latest) in &entries {
+        lines.push(list_entry(sub, latest.as_ref(), sort));
+
+        let arg = format!("{page}:{}:{}", sort.as_str(), sub.krate);
+        if let Some(button) = callback_button(&format!("✖ {}", sub.krate), LIST_UNSUBSCRIBE, &arg)
+        {
+            rows.push(vec![button]);
         }
     }
 
-    Ok(subscriptions)
+    let other = match sort {
+        Sort::Name => (Sort::Released, "Sort by release"),
+        Sort::Released => (Sort::Name, "Sort by name"),
+    };
+    let navigation = [
+        (page > 0).then(|| ("« Prev", page - 1, sort)),
+        Some((other.1, 0, other.0)),
+        (page < last_page).then(|| ("Next »", page + 1, sort)),
+    ];
+    rows.push(
+        navigation
+            .iter()
+            .flatten()
+            .filter_map(|&(text, page, sort)| {
+                callback_button(text, LIST, &format!("{page}:{}", sort.as_str()))
+            })
+            .collect(),
+    );
+
+    let text = format!(
+        "You are currently subscribed to (page {}/{}):\n— {}",
+        page + 1,
+        last_page + 1,
+        lines.join("\n— ")
+    );
+
+    Ok((text, Some(InlineKeyboardMarkup::new(rows))))
+}
+
+/// The latest version of the crate (`None` if it couldn't be read) and its
+/// registry.
+async fn latest<'a>(krate: &str, cfg: &'a Config) -> Option<(Crate, &'a Registry)> {
+    let (registry, name) = cfg.resolve(krate)?;
+
+    Crate::read_last(name, registry)
+        .await
+        .ok()
+        .map(|krate| (krate, registry))
+}
+
+/// Html line describing the subscription in `/list`.
+fn list_entry(sub: &Subscription, latest: Option<&(Crate, &Registry)>, sort: Sort) -> String {
+    let mut entry = format!("<code>{}", sub.krate);
+
+    match latest {
+        Some((krate, _)) => {
+            entry.push('#');
+            entry.push_str(&krate.id.vers);
+            entry.push_str("</code>");
+        }
+        None => {
+            entry.push_str(" </code>");
+            /* silently ignore error & just don't add links */
+        }
+    }
+
+    if let Some(req) = &sub.req {
+        entry.push_str(" (<code>");
+        entry.push_str(&html::escape(&req.to_string()));
+        entry.push_str("</code>)");
+    }
+
+    if sub.filter != Filter::ALL {
+        entry.push_str(" [");
+        entry.push_str(&sub.filter.to_string());
+        entry.push(']');
+    }
+
+    if let Some((krate, registry)) = latest {
+        // Date of the release, e.g. `2023-01-20`
+        if let (Sort::Released, Some(pubtime)) = (sort, &krate.pubtime) {
+            entry.push_str(" released ");
+            entry.push_str(pubtime.get(..10).unwrap_or(pubtime));
+        }
+
+        entry.push(' ');
+        entry.push_str(&krate.html_links(registry));
+    }
+
+    entry
+}
+
+/// Inline query result with the latest version of `krate`, links and a button
+/// to subscribe to it. Returns `None` if there is no such crate.
+async fn crate_card(krate: &str, idx: usize, cfg: &Config) -> Option<InlineQueryResult> {
+    let (registry, name) = cfg.resolve(krate)?;
+    let krate = Crate::find(name, registry).await.ok()?;
+    let name = registry.qualify(&krate.id.name);
+
+    let text = format!(
+        "Crate <code>{name}#{}</code> {}",
+        krate.id.vers,
+        krate.html_links(registry)
+    );
+    let content = InputMessageContentText::new(text)
+        .parse_mode(ParseMode::Html)
+        .disable_web_page_preview(true);
+
+    let mut article = InlineQueryResultArticle::new(
+        idx.to_string(),
+        name.clone(),
+        InputMessageContent::Text(content),
+    )
+    .description(format!("Latest version: {}", krate.id.vers));
+
+    if let Some(button) = callback_button("Subscribe", SUBSCRIBE, &name) {
+        article = article.reply_markup(InlineKeyboardMarkup::new([[button]]));
+    }
+
+    Some(InlineQueryResult::Article(article))
+}
+
+/// Results of a command applied to several crates, lists of html formatted
+/// crates.
+#[derive(Default)]
+struct Summary {
+    /// Crates the command succeeded for
+    done: Vec<String>,
+    /// Crates the command had nothing to do with (e.g. already subscribed)
+    already: Vec<String>,
+    /// Crates which don't exist
+    missing: Vec<String>,
+    /// Crates with invalid version requirements
+    invalid: Vec<String>,
+}
+
+impl Summary {
+    fn message(&self, done: &str, already: &str) -> String {
+        let sections = [
+            (done, &self.done),
+            (already, &self.already),
+            ("There are no such crates", &self.missing),
+            ("Invalid version requirements", &self.invalid),
+        ];
+
+        sections
+            .iter()
+            .filter(|(_, crates)| !crates.is_empty())
+            .map(|(title, crates)| format!("{title}:\n— {}", crates.join("\n— ")))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// Normalized (see [`normalized`]) names of crates the chat is subscribed to.
+async fn subscribed(chat_id: ChatId, db: &Database) -> Result<HashSet<String>, HErr> {
+    let subscriptions = db.list_subscriptions(chat_id).await?;
+
+    Ok(subscriptions
+        .map(|sub| normalize_name(&sub.krate))
+        .collect())
 }
 
 async fn check_privileges(bot: &Bot, msg: &Message) -> Result<(), HErr> {
     if !msg.chat.is_private() {
-        let admins = bot.get_chat_administrators(msg.chat.id).await?;
-
         let user_id = msg.from().ok_or(HErr::GetUser)?.id;
-        if admins
-            .iter()
-            .map(|admin| admin.user.id)
-            .any(|id| id == user_id)
-            .not()
-        {
+        if is_admin(bot, msg.chat.id, user_id).await?.not() {
             return Err(HErr::NotAdmin);
         }
     };
@@ -218,34 +1045,264 @@ async fn check_privileges(bot: &Bot, msg: &Message) -> Result<(), HErr> {
     Ok(())
 }
 
+/// Describes how updates are delivered, e.g. "sent in a daily digest at 09:00
+/// (UTC)".
+fn describe_delivery(settings: &Settings) -> String {
+    let Settings {
+        delivery,
+        digest_hour: hour,
+        timezone,
+        ..
+    } = settings;
+
+    match delivery {
+        Delivery::Instant => String::from("sent as soon as they are published"),
+        Delivery::Daily => format!("sent in a daily digest at {hour:02}:00 ({timezone})"),
+        Delivery::Weekly => {
+            format!("sent in a weekly digest on mondays at {hour:02}:00 ({timezone})")
+        }
+    }
+}
+
+/// Describes quiet hours, e.g. "notifications from 22:00 to 08:00 (UTC) are
+/// sent silently".
+fn describe_quiet_hours(settings: &Settings) -> String {
+    match settings.quiet {
+        Some(QuietHours { start, end, mode }) => format!(
+            "notifications from {start:02}:00 to {end:02}:00 ({}) are {}",
+            settings.timezone,
+            match mode {
+                QuietMode::Silent => "sent silently",
+                QuietMode::Hold => "held until quiet hours end",
+            }
+        ),
+        None => String::from("there are no quiet hours"),
+    }
+}
+
+/// Replaces `/list` message `msg` with page `page`.
+async fn edit_list(
+    bot: &Bot,
+    msg: &Message,
+    page: usize,
+    sort: Sort,
+    db: &Database,
+    cfg: &Config,
+) -> Result<(), HErr> {
+    let (text, keyboard) = list_page(msg.chat.id, page, sort, db, cfg).await?;
+
+    // Without a keyboard the old one is removed
+    let request = bot
+        .edit_message_text(msg.chat.id, msg.id, text)
+        .disable_web_page_preview(true);
+    match keyboard {
+        Some(keyboard) => request.reply_markup(keyboard).await?,
+        None => request.await?,
+    };
+
+    Ok(())
+}
+
+/// Returns `true` if the user is an administrator of the (non-private) chat.
+async fn is_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> Result<bool, HErr> {
+    let admins = bot.get_chat_administrators(chat_id).await?;
+
+    Ok(admins.iter().any(|admin| admin.user.id == user_id))
+}
+
+/// Inline keyboard attached to notifications about updates of `krate`.
+pub fn notification_keyboard(krate: &str, vers: &str, action: &ActionKind) -> InlineKeyboardMarkup {
+    let mut buttons = vec![
+        callback_button("Unsubscribe", UNSUBSCRIBE, krate),
+        callback_button(&format!("Mute {MUTE_DAYS} days"), MUTE, krate),
+    ];
+
+    if let ActionKind::NewVersion = action {
+        let krate = format!("{krate}#{vers}");
+        buttons.push(callback_button("Show deps diff", DEPS, &krate));
+    }
+
+    InlineKeyboardMarkup::new([buttons.into_iter().flatten()])
+}
+
+/// Button which sends callback query with `action:arg` data. Returns `None` if
+/// the data doesn't fit telegram limits (64 bytes).
+fn callback_button(text: &str, action: &str, arg: &str) -> Option<InlineKeyboardButton> {
+    let data = format!("{action}:{arg}");
+
+    (data.len() <= 64).then(|| InlineKeyboardButton::callback(text, data))
+}
+
+/// Html describing what changed in version `vers` of `krate` compared to the
+/// previous version (dependencies, features and MSRV).
+async fn deps_diff(krate: &str, vers: &str, cfg: &Config) -> String {
+    // `krate` and `vers` come from callback data (i.e. from the client), so
+    // they are escaped, or replaced with the ones read from the index
+    let versions = match cfg.resolve(krate) {
+        Some((registry, name)) => Crate::read_all(name, registry)
+            .await
+            .ok()
+            .map(|versions| (registry, versions)),
+        None => None,
+    };
+
+    let (registry, versions) = match versions {
+        Some(found) => found,
+        None => {
+            return format!(
+                "Error: there is no such crate <code>{}</code>.",
+                html::escape(krate)
+            )
+        }
+    };
+
+    let (prev, next) = match versions.iter().position(|k| k.id.vers == vers) {
+        Some(idx) if idx > 0 => (&versions[idx - 1], &versions[idx]),
+        Some(_) => {
+            return format!(
+                "<code>{}#{}</code> is the first version of the crate.",
+                registry.qualify(&versions[0].id.name),
+                versions[0].id.vers
+            )
+        }
+        None => {
+            return format!(
+                "Error: there is no such version <code>{}#{}</code>.",
+                html::escape(krate),
+                html::escape(vers)
+            )
+        }
+    };
+
+    let krate = registry.qualify(&next.id.name);
+    let vers = &next.id.vers;
+
+    let changes = next.changes_since(prev);
+    if changes.is_empty() {
+        return format!(
+            "Dependencies, features and MSRV of <code>{krate}#{vers}</code> are the same as of \
+             {}.",
+            prev.id.vers
+        );
+    }
+
+    let mut text = format!(
+        "Changes of <code>{krate}</code> {} → {vers}:\n",
+        prev.id.vers
+    );
+    for change in changes {
+        // Telegram limits messages to 4096 characters
+        if text.len() + change.len() > 4000 {
+            text.push_str("\n…");
+            break;
+        }
+
+        text.push('\n');
+        text.push_str(&change);
+    }
+
+    text
+}
+
+/// Subscribes the chat to the crate. Returns `None` if there is no such crate,
+/// otherwise the exact name of the crate (`krate` may differ from it in case or
+/// `-`/`_`) and html with its current version.
 async fn subscribe(
     chat_id: ChatId,
     krate: &str,
+    req: Option<&VersionReq>,
     db: &Database,
     cfg: &Config,
-) -> Result<Option<String>, HErr> {
-    if PathBuf::from(cfg.index_path.as_str())
-        .also(|p| p.push(crate_path(krate)))
-        .exists()
-    {
-        db.subscribe(chat_id, krate).await?;
-
-        let ver = match Crate::read_last(krate, cfg).await {
-            Ok(krate) => format!(
+) -> Result<Option<(String, String)>, HErr> {
+    let (registry, name) = match cfg.resolve(krate) {
+        Some(resolved) => resolved,
+        None => return Ok(None),
+    };
+
+    match Crate::find(name, registry).await {
+        Ok(krate) => {
+            let name = registry.qualify(&krate.id.name);
+            db.subscribe(chat_id, &name, req).await?;
+
+            let ver = format!(
                 " (current version <code>{}</code> {})",
                 krate.id.vers,
-                krate.html_links()
-            ),
-            Err(_) => String::new(),
-        };
+                krate.html_links(registry)
+            );
 
-        Ok(Some(ver))
-    } else {
-        Ok(None)
+            Ok(Some((name, ver)))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Name of the crate as it's stored in the db (e.g. `crates-io:serde` is
+/// stored as just `serde`).
+fn qualified(krate: &str, cfg: &Config) -> String {
+    match cfg.resolve(krate) {
+        Some((registry, name)) => registry.qualify(name),
+        None => krate.to_owned(),
     }
 }
 
-fn opt(input: String) -> Result<(Option<String>,), ParseError> {
+/// Qualified name of the crate, normalized so that names which only differ in
+/// case or `-`/`_` are equal.
+fn normalized(krate: &str, cfg: &Config) -> String {
+    normalize_name(&qualified(krate, cfg))
+}
+
+/// Parses `<crate> [rest]`, where the rest may contain spaces (e.g. version
+/// requirement `>=1.2, <1.5`).
+fn crate_args(input: String) -> Result<(OptString, OptString), ParseError> {
+    let input = input.trim();
+    let res = match input.split_once(char::is_whitespace) {
+        _ if input.is_empty() => (None, None),
+        None => (Some(input.to_owned()), None),
+        Some((krate, req)) => (Some(krate.to_owned()), Some(req.trim().to_owned())),
+    };
+
+    Ok(res)
+}
+
+/// Parses a list of crates, each optionally followed by a version requirement,
+/// separated by spaces or commas (e.g. `serde ^1, tokio >=1.2, <1.5 log`).
+///
+/// Crate names start with a letter, so everything else is a part of the
+/// requirement of the preceding crate.
+fn crate_list(input: String) -> Result<(Vec<(String, OptString)>,), ParseError> {
+    let mut crates = Vec::<(String, OptString)>::new();
+
+    // Whether the current token is separated from the previous one by a comma
+    let mut comma = false;
+    for word in input.split_whitespace() {
+        for (idx, token) in word.split(',').enumerate() {
+            comma |= idx > 0;
+            if token.is_empty() {
+                continue;
+            }
+
+            match crates.last_mut() {
+                Some((_, req)) if !token.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    match req {
+                        Some(req) => {
+                            req.push_str(if comma { ", " } else { " " });
+                            req.push_str(token);
+                        }
+                        None => *req = Some(token.to_owned()),
+                    }
+                }
+                _ => crates.push((token.to_owned(), None)),
+            }
+
+            comma = false;
+        }
+    }
+
+    Ok((crates,))
+}
+
+/// Parses an optional argument.
+fn opt(input: String) -> Result<(OptString,), ParseError> {
     match input.split_whitespace().count() {
         0 => Ok((None,)),
         1 => Ok((Some(input.trim().to_owned()),)),
@@ -256,3 +1313,161 @@ fn opt(input: String) -> Result<(Option<String>,), ParseError> {
         }),
     }
 }
+
+/// Parses an hour of a day (`0`..=`23`).
+fn parse_hour(hour: &str) -> Option<i32> {
+    hour.parse().ok().filter(|hour| (0..24).contains(hour))
+}
+
+/// Parses arguments of `/quiet` (the start hour and the rest, i.e. the end
+/// hour optionally followed by the mode). Returns the error message (html)
+/// if they are invalid.
+fn parse_quiet(start: &str, rest: &str) -> Result<QuietHours, String> {
+    let mut rest = rest.split_whitespace();
+    let hours = (parse_hour(start), rest.next().and_then(parse_hour));
+
+    let mode = rest
+        .next()
+        .map(str::parse::<QuietMode>)
+        .transpose()
+        .map_err(|err| html::escape(&err))?
+        .unwrap_or(QuietMode::Silent);
+
+    match hours {
+        (Some(start), Some(end)) if start != end => Ok(QuietHours { start, end, mode }),
+        _ => Err(String::from(
+            "quiet hours must start and end at different hours from 0 to 23",
+        )),
+    }
+}
+
+/// Parses a list of crates separated by spaces or commas.
+fn names(input: String) -> Result<(Vec<String>,), ParseError> {
+    let names = input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    Ok((names,))
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::VersionReq;
+    use teloxide::utils::command::{BotCommands, ParseError};
+
+    use super::{crate_list, parse_hour, parse_quiet, Command};
+    use crate::db::{QuietHours, QuietMode};
+
+    fn list(input: &str) -> Vec<(String, Option<String>)> {
+        crate_list(input.to_owned()).unwrap().0
+    }
+
+    fn krate(name: &str, req: Option<&str>) -> (String, Option<String>) {
+        (name.to_owned(), req.map(str::to_owned))
+    }
+
+    #[test]
+    fn crates() {
+        assert_eq!(list(""), []);
+        assert_eq!(list(" , ,"), []);
+        assert_eq!(
+            list("serde,tokio  log , rand"),
+            [
+                krate("serde", None),
+                krate("tokio", None),
+                krate("log", None),
+                krate("rand", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn crates_with_requirements() {
+        assert_eq!(
+            list("serde ^1, tokio >=1.2, <1.5 log"),
+            [
+                krate("serde", Some("^1")),
+                krate("tokio", Some(">=1.2, <1.5")),
+                krate("log", None),
+            ]
+        );
+        assert_eq!(
+            list("serde = 1.0.100,tokio 1"),
+            [krate("serde", Some("= 1.0.100")), krate("tokio", Some("1"))]
+        );
+        assert_eq!(list("reg:krate ~2"), [krate("reg:krate", Some("~2"))]);
+
+        // Requirements are validated later, when subscribing
+        let bad = list("serde =>1");
+        assert_eq!(bad, [krate("serde", Some("=>1"))]);
+        assert!(bad[0].1.as_deref().unwrap().parse::<VersionReq>().is_err());
+
+        // A requirement without a crate is taken as a name
+        assert_eq!(list("^1 serde"), [krate("^1", None), krate("serde", None)]);
+    }
+
+    #[test]
+    fn optional_argument() {
+        let parse = |text| Command::parse(text, "bot");
+
+        assert_eq!(parse("/unmute").unwrap(), Command::Unmute(None));
+        assert_eq!(
+            parse("/unmute  serde ").unwrap(),
+            Command::Unmute(Some("serde".into()))
+        );
+        assert!(matches!(
+            parse("/unmute foo bar"),
+            Err(ParseError::TooManyArguments { found: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn hour() {
+        assert_eq!(parse_hour("0"), Some(0));
+        assert_eq!(parse_hour("23"), Some(23));
+        assert_eq!(parse_hour("24"), None);
+        assert_eq!(parse_hour("-1"), None);
+        assert_eq!(parse_hour("8am"), None);
+    }
+
+    #[test]
+    fn quiet_command() {
+        let parse = |text| Command::parse(text, "bot").unwrap();
+
+        assert_eq!(parse("/quiet"), Command::Quiet(None, None));
+        assert_eq!(
+            parse("/quiet off"),
+            Command::Quiet(Some("off".into()), None)
+        );
+        assert_eq!(
+            parse("/quiet 22  8 hold"),
+            Command::Quiet(Some("22".into()), Some("8 hold".into()))
+        );
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let quiet = |start, end, mode| QuietHours { start, end, mode };
+
+        assert_eq!(parse_quiet("22", "8"), Ok(quiet(22, 8, QuietMode::Silent)));
+        assert_eq!(
+            parse_quiet("0", "7 silent"),
+            Ok(quiet(0, 7, QuietMode::Silent))
+        );
+        assert_eq!(
+            parse_quiet("22", "8 hold"),
+            Ok(quiet(22, 8, QuietMode::Hold))
+        );
+
+        // The mode is checked first and escaped since it's echoed back
+        assert_eq!(
+            parse_quiet("22", "24 <b>"),
+            Err(String::from("unknown quiet hours mode `&lt;b&gt;`"))
+        );
+        assert!(parse_quiet("22", "24").is_err());
+        assert!(parse_quiet("8", "8").is_err());
+        assert!(parse_quiet("22", "").is_err());
+    }
+}
@@ -1,12 +1,22 @@
 use fntools::value::ValueExt;
-use std::{collections::HashSet, error::Error, fs::File, io::Read, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    fs::File,
+    io::Read,
+    time::Duration,
+};
 use teloxide::types::ChatId;
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Config {
-    /// Channel to post **ALL** updates
+    /// Channel to post **ALL** updates of crates.io
     #[serde(default)]
     pub channel: Option<ChatId>,
+    /// Collect updates arriving one after another into a single channel post
+    /// (applies to channels of all registries)
+    #[serde(default)]
+    pub channel_aggregation: Option<Aggregation>,
     #[serde(default)]
     pub error_report_channel_id: Option<ChatId>,
     /// Delay between index fetches
@@ -15,16 +25,23 @@ pub struct Config {
     /// Logging level
     #[serde(default = "defaults::loglevel")]
     pub loglevel: log::LevelFilter,
+    /// Which index to use to get updates
+    #[serde(default)]
+    pub index_backend: IndexBackend,
     /// Url of crates.io index (git repo)
     #[serde(default = "defaults::index_url")]
     pub index_url: String,
     /// The path to the local crates.io index git repository
     #[serde(default = "defaults::index_path")]
     pub index_path: String,
+    /// Url of sparse crates.io index
+    #[serde(default = "defaults::sparse_index_url")]
+    pub sparse_index_url: String,
     /// Delay after which bot will retry telegram-request
     #[serde(default)]
     pub retry_delay: RetryDelay,
-    /// Delay between broadcast send messages
+    /// Minimal delay between any two notifications (telegram allows about 30
+    /// messages per second)
     #[serde(default)]
     pub broadcast_delay_millis: BroadcastDelay,
     /// Delay between notifying about updates
@@ -37,13 +54,204 @@ pub struct Config {
     /// Ban configuration
     #[serde(default)]
     pub ban: BanConfig,
+    /// Registries to watch, by name. crates.io registry (named `crates-io`) is
+    /// always added using top-level index settings, it can't be configured
+    /// here.
+    #[serde(default)]
+    pub registries: BTreeMap<String, Registry>,
 }
 
 impl Config {
     pub fn read() -> Result<Self, Box<dyn Error>> {
         let mut str = String::new();
         File::open("./config.toml")?.read_to_string(&mut str)?;
-        Ok(toml::from_str(&str)?)
+        Self::parse(&str)
+    }
+
+    /// Parses the config and fills in the registries (see
+    /// [`Config::registries`]).
+    pub fn parse(str: &str) -> Result<Self, Box<dyn Error>> {
+        let mut this: Self = toml::from_str(str)?;
+
+        // It would silently replace top-level settings (or be ignored)
+        if this.registries.contains_key(CRATES_IO) {
+            return Err(format!(
+                "registry `{CRATES_IO}` is configured with top-level settings, remove \
+                 `[registries.{CRATES_IO}]`"
+            )
+            .into());
+        }
+
+        for (name, registry) in &mut this.registries {
+            let ok = match registry.index_backend {
+                IndexBackend::Git => {
+                    !(registry.index_url.is_empty() || registry.index_path.is_empty())
+                }
+                IndexBackend::Sparse => !registry.sparse_index_url.is_empty(),
+            };
+
+            if !ok {
+                return Err(format!("registry `{name}` doesn't have index url/path set").into());
+            }
+
+            registry.name = name.clone();
+        }
+
+        let crates_io = Registry {
+            name: String::from(CRATES_IO),
+            index_backend: this.index_backend,
+            index_url: this.index_url.clone(),
+            index_path: this.index_path.clone(),
+            sparse_index_url: this.sparse_index_url.clone(),
+            channel: this.channel,
+            links: defaults::crates_io_links(),
+            http: reqwest::Client::new(),
+        };
+        this.registries.insert(String::from(CRATES_IO), crates_io);
+
+        Ok(this)
+    }
+
+    /// Splits qualified crate name (`registry:crate`, or just `crate` for
+    /// crates.io) into the registry and the crate name. Returns `None` if
+    /// there is no such registry or the name can't be a crate name (crate
+    /// names only consist of ascii letters, digits, `-` and `_`).
+    pub fn resolve<'a>(&self, qualified: &'a str) -> Option<(&Registry, &'a str)> {
+        let (registry, krate) = qualified.split_once(':').unwrap_or((CRATES_IO, qualified));
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if krate.is_empty() || !krate.chars().all(valid) {
+            return None;
+        }
+
+        self.registries.get(registry).map(|r| (r, krate))
+    }
+}
+
+/// Name of the crates.io registry, crates from it are referred to without a
+/// registry qualifier.
+pub const CRATES_IO: &str = "crates-io";
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Registry {
+    /// Name of the registry (key in the `registries` table)
+    #[serde(skip)]
+    pub name: String,
+    /// Which index to use to get updates
+    #[serde(default)]
+    pub index_backend: IndexBackend,
+    /// Url of the index (git repo)
+    #[serde(default)]
+    pub index_url: String,
+    /// The path to the local index git repository
+    #[serde(default)]
+    pub index_path: String,
+    /// Url of sparse index
+    #[serde(default)]
+    pub sparse_index_url: String,
+    /// Channel to post **ALL** updates of this registry
+    #[serde(default)]
+    pub channel: Option<ChatId>,
+    /// Links added to messages about crates
+    #[serde(default)]
+    pub links: Vec<Link>,
+    /// Client used to fetch the sparse index (clones of the registry share its
+    /// connection pool)
+    #[serde(skip)]
+    pub http: reqwest::Client,
+}
+
+impl Registry {
+    /// Name of the crate as it's stored in the db (i.e. `registry:crate`, or
+    /// just `crate` for crates.io).
+    pub fn qualify(&self, krate: &str) -> String {
+        if self.name == CRATES_IO {
+            krate.to_owned()
+        } else {
+            format!("{}:{krate}", self.name)
+        }
+    }
+
+    /// Returns `true` if `source` of a package from `Cargo.lock` (e.g.
+    /// `registry+https://github.com/rust-lang/crates.io-index`) is this
+    /// registry.
+    pub fn is_source(&self, source: &str) -> bool {
+        let normalize = |url: &str| {
+            url.trim_end_matches('/')
+                .trim_end_matches(".git")
+                .to_owned()
+        };
+        let same = |url: &str, ours: &str| !ours.is_empty() && normalize(url) == normalize(ours);
+
+        match source.split_once('+') {
+            Some(("registry", url)) => same(url, &self.index_url),
+            Some(("sparse", url)) => same(url, &self.sparse_index_url),
+            _ => false,
+        }
+    }
+
+    /// Reverse of [`Registry::qualify`], returns `None` if the crate is from
+    /// another registry.
+    pub fn unqualify<'a>(&self, qualified: &'a str) -> Option<&'a str> {
+        match qualified.split_once(':') {
+            Some((registry, krate)) if registry == self.name => Some(krate),
+            None if self.name == CRATES_IO => Some(qualified),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Link {
+    /// Text of the link
+    pub name: String,
+    /// Url template, `{name}` and `{version}` are replaced with the crate name
+    /// and version
+    pub url: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexBackend {
+    /// Clone the whole git index and walk through its commits
+    #[default]
+    Git,
+    /// Poll files of subscribed crates from the sparse index over HTTP
+    Sparse,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Aggregation {
+    /// For how long (since the post was started) new updates are added to it
+    #[serde(default = "defaults::aggregation_window")]
+    pub window: Duration,
+    /// Which updates are collected into the same post
+    #[serde(default)]
+    pub group_by: Grouping,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grouping {
+    /// Crates with the same name prefix, i.e. the part before the first `-` or
+    /// `_` (`tokio`, `tokio-util` and `tokio_stream` are in the same post)
+    #[default]
+    Prefix,
+    /// All crates published within the window
+    Time,
+}
+
+impl Grouping {
+    /// Key of the group the crate (qualified name) belongs to.
+    pub fn key(self, krate: &str) -> String {
+        match self {
+            Self::Prefix => krate
+                .split(['-', '_'])
+                .next()
+                .unwrap_or(krate)
+                .to_lowercase(),
+            Self::Time => String::new(),
+        }
     }
 }
 
@@ -122,6 +330,10 @@ mod defaults {
         Duration::from_secs(60 * 5) // 5 min
     }
 
+    pub(super) const fn aggregation_window() -> Duration {
+        Duration::from_secs(60 * 2) // 2 min
+    }
+
     pub(super) const fn loglevel() -> log::LevelFilter {
         log::LevelFilter::Info
     }
@@ -133,4 +345,66 @@ mod defaults {
     pub(super) fn index_path() -> String {
         String::from("./index")
     }
+
+    pub(super) fn sparse_index_url() -> String {
+        String::from("https://index.crates.io")
+    }
+
+    pub(super) fn crates_io_links() -> Vec<super::Link> {
+        let link = |name: &str, url: &str| super::Link {
+            name: name.to_owned(),
+            url: url.to_owned(),
+        };
+
+        vec![
+            // Note:
+            // The full url is actually "https://docs.rs/{krate}/{version}/{krate}"
+            // but for some crates it doesn't hold e.g.: https://docs.rs/lsk/0.2.0/ls_key/
+            // Names differ                                              ^^^       ^^^^^^
+            //
+            // Anyway, "https://docs.rs/{krate}/{version}" redirects to the right place
+            link("docs.rs", "https://docs.rs/{name}/{version}"),
+            link("crates.io", "https://crates.io/crates/{name}"),
+            link("lib.rs", "https://lib.rs/crates/{name}"),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Grouping, CRATES_IO};
+
+    const REQUIRED: &str = r#"
+        bot_token = ""
+        db = { host = "", user = "", dbname = "" }
+    "#;
+
+    #[test]
+    fn crates_io_registry() {
+        let cfg = Config::parse(REQUIRED).unwrap();
+        let crates_io = &cfg.registries[CRATES_IO];
+        assert_eq!(crates_io.name, CRATES_IO);
+        assert_eq!(crates_io.index_url, cfg.index_url);
+        assert!(!crates_io.links.is_empty());
+
+        let redefined = format!(
+            "{REQUIRED}\n[registries.crates-io]\nsparse_index_url = \"https://example.com\""
+        );
+        let err = Config::parse(&redefined).unwrap_err();
+        assert!(err.to_string().contains("top-level settings"));
+    }
+
+    #[test]
+    fn group_key() {
+        let prefix = |krate| Grouping::Prefix.key(krate);
+
+        assert_eq!(prefix("tokio"), "tokio");
+        assert_eq!(prefix("tokio-util"), "tokio");
+        assert_eq!(prefix("tokio_stream"), "tokio");
+        assert_eq!(prefix("Tokio-Macros"), "tokio");
+        assert_eq!(prefix("corp:tokio-util"), "corp:tokio");
+        assert_ne!(prefix("tokio-util"), prefix("tower"));
+
+        assert_eq!(Grouping::Time.key("tokio"), Grouping::Time.key("serde"));
+    }
 }
@@ -1,10 +1,13 @@
 use futures::Future;
-use teloxide::types::ChatId;
+use semver::VersionReq;
+use teloxide::types::{ChatId, InlineKeyboardMarkup, MessageId};
 use tokio_postgres::{
     tls::MakeTlsConnect, types::Type, Client, Config, Connection, Error, Socket, Statement,
 };
 
-use std::sync::Arc;
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use crate::index::{ActionKind, Cursor, Update};
 
 #[derive(Clone)]
 pub struct Database {
@@ -32,10 +35,16 @@ impl Database {
         Ok((this, connection))
     }
 
-    pub async fn subscribe(&self, ChatId(chat_id): ChatId, krate: &str) -> Result<(), Error> {
+    pub async fn subscribe(
+        &self,
+        ChatId(chat_id): ChatId,
+        krate: &str,
+        req: Option<&VersionReq>,
+    ) -> Result<(), Error> {
         let stmt = &self.prepared.subscribe;
+        let req = req.map(VersionReq::to_string);
 
-        self.inner.execute(stmt, &[&chat_id, &krate]).await?;
+        self.inner.execute(stmt, &[&chat_id, &krate, &req]).await?;
 
         Ok(())
     }
@@ -51,7 +60,7 @@ impl Database {
     pub async fn list_subscribers(
         &self,
         krate: &str,
-    ) -> Result<impl Iterator<Item = ChatId>, Error> {
+    ) -> Result<impl Iterator<Item = Subscriber>, Error> {
         let stmt = &self.prepared.list_subscribers;
 
         let res = self
@@ -59,16 +68,72 @@ impl Database {
             .query(stmt, &[&krate])
             .await?
             .into_iter()
-            .map(|row| row.get(0))
-            .map(ChatId);
+            .map(|row| Subscriber {
+                chat_id: ChatId(row.get(0)),
+                req: parse_req(row.get(1)),
+                filter: Filter::from_row(&row, 2),
+                muted: row.get(6),
+                digest: row.get(7),
+                quiet: row
+                    .get::<_, Option<&str>>(8)
+                    .and_then(|mode| mode.parse().ok()),
+            });
+
+        Ok(res)
+    }
+
+    /// Lists all crates which have at least one subscriber (or watcher).
+    pub async fn list_crates(&self) -> Result<impl Iterator<Item = String>, Error> {
+        let stmt = &self.prepared.list_crates;
+
+        let res = self
+            .inner
+            .query(stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Returns the last processed commit of the registry index.
+    pub async fn get_cursor(&self, registry: &str) -> Result<Option<String>, Error> {
+        let stmt = &self.prepared.get_cursor;
+
+        let row = self.inner.query_opt(stmt, &[&registry]).await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Returns the last seen states of crate files of the (sparse) registry
+    /// index, by crate name.
+    pub async fn sparse_files(&self, registry: &str) -> Result<Vec<(String, String)>, Error> {
+        let stmt = &self.prepared.sparse_files;
+
+        let res = self
+            .inner
+            .query(stmt, &[&registry])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
 
         Ok(res)
     }
 
+    /// Sets the last processed commit of the registry index.
+    pub async fn set_cursor(&self, registry: &str, commit: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.set_cursor;
+
+        self.inner.execute(stmt, &[&registry, &commit]).await?;
+
+        Ok(())
+    }
+
     pub async fn list_subscriptions(
         &self,
         ChatId(chat_id): ChatId,
-    ) -> Result<impl Iterator<Item = String>, Error> {
+    ) -> Result<impl Iterator<Item = Subscription>, Error> {
         let stmt = &self.prepared.list_subscriptions;
 
         let res = self
@@ -76,10 +141,950 @@ impl Database {
             .query(stmt, &[&chat_id])
             .await?
             .into_iter()
-            .map(|row| row.get(0));
+            .map(|row| Subscription {
+                krate: row.get(0),
+                req: parse_req(row.get(1)),
+                filter: Filter::from_row(&row, 2),
+            });
+
+        Ok(res)
+    }
+
+    /// Replaces subscriptions made from the previously uploaded lockfile with
+    /// subscriptions to `crates`. Subscriptions made manually are not
+    /// affected.
+    ///
+    /// Versions watched because of the previous lockfile are replaced with
+    /// `versions` (crate names and exact versions).
+    pub async fn replace_lockfile(
+        &self,
+        ChatId(chat_id): ChatId,
+        crates: &[(String, VersionReq)],
+        versions: &[(String, String)],
+    ) -> Result<LockfileChanges, Error> {
+        let stmt = &self.prepared.replace_lockfile;
+
+        let (names, reqs): (Vec<_>, Vec<_>) = crates
+            .iter()
+            .map(|(krate, req)| (krate.as_str(), req.to_string()))
+            .unzip();
+
+        let (watched, versions): (Vec<_>, Vec<_>) = versions
+            .iter()
+            .map(|(krate, version)| (krate.as_str(), version.as_str()))
+            .unzip();
+
+        let row = self
+            .inner
+            .query_one(stmt, &[&chat_id, &names, &reqs, &watched, &versions])
+            .await?;
+
+        Ok(LockfileChanges {
+            added: row.get(0),
+            updated: row.get(1),
+            removed: row.get(2),
+        })
+    }
+
+    /// Starts watching exact `version` of the crate, the chat is alerted when
+    /// it's yanked.
+    pub async fn watch(
+        &self,
+        ChatId(chat_id): ChatId,
+        krate: &str,
+        version: &str,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.watch;
+
+        self.inner
+            .execute(stmt, &[&chat_id, &krate, &version])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stops watching the version. Returns `false` if the chat didn't watch it.
+    pub async fn unwatch(
+        &self,
+        ChatId(chat_id): ChatId,
+        krate: &str,
+        version: &str,
+    ) -> Result<bool, Error> {
+        let stmt = &self.prepared.unwatch;
+
+        let row = self
+            .inner
+            .query_one(stmt, &[&chat_id, &krate, &version])
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Lists chats watching exact `version` of the crate.
+    pub async fn list_watchers(
+        &self,
+        krate: &str,
+        version: &str,
+    ) -> Result<impl Iterator<Item = ChatId>, Error> {
+        let stmt = &self.prepared.list_watchers;
+
+        let res = self
+            .inner
+            .query(stmt, &[&krate, &version])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)));
+
+        Ok(res)
+    }
+
+    /// Unmutes the subscription, `false` if the chat is not subscribed to it.
+    pub async fn unmute(&self, ChatId(chat_id): ChatId, krate: &str) -> Result<bool, Error> {
+        let stmt = &self.prepared.unmute;
+
+        let row = self.inner.query_one(stmt, &[&chat_id, &krate]).await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Mutes the subscription for `days` days. Returns `false` if the chat is
+    /// not subscribed to the crate.
+    pub async fn mute(
+        &self,
+        ChatId(chat_id): ChatId,
+        krate: &str,
+        days: i32,
+    ) -> Result<bool, Error> {
+        let stmt = &self.prepared.mute;
+
+        let row = self
+            .inner
+            .query_one(stmt, &[&chat_id, &krate, &days])
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Returns settings of the chat (the defaults if they were never changed).
+    pub async fn settings(&self, ChatId(chat_id): ChatId) -> Result<Settings, Error> {
+        let stmt = &self.prepared.get_settings;
+
+        let row = self.inner.query_opt(stmt, &[&chat_id]).await?;
+
+        let res = match row {
+            Some(row) => Settings {
+                delivery: row.get::<_, &str>(0).parse().unwrap_or(Delivery::Instant),
+                digest_hour: row.get(1),
+                timezone: row.get(2),
+                quiet: match (row.get(3), row.get(4)) {
+                    (Some(start), Some(end)) => Some(QuietHours {
+                        start,
+                        end,
+                        mode: row.get::<_, &str>(5).parse().unwrap_or(QuietMode::Silent),
+                    }),
+                    _ => None,
+                },
+            },
+            None => Settings::default(),
+        };
 
         Ok(res)
     }
+
+    /// Sets how updates are delivered to the chat, digests are sent at `hour`
+    /// o'clock (in the chat timezone).
+    pub async fn set_delivery(
+        &self,
+        ChatId(chat_id): ChatId,
+        delivery: Delivery,
+        hour: i32,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.set_delivery;
+
+        self.inner
+            .execute(stmt, &[&chat_id, &delivery.as_str(), &hour])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sets the timezone of the chat. Returns the canonical name of the
+    /// timezone, or `None` if there is no such timezone.
+    pub async fn set_timezone(
+        &self,
+        ChatId(chat_id): ChatId,
+        timezone: &str,
+    ) -> Result<Option<String>, Error> {
+        let stmt = &self.prepared.set_timezone;
+
+        let row = self.inner.query_one(stmt, &[&chat_id, &timezone]).await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Sets (or removes, if `quiet` is `None`) quiet hours of the chat.
+    pub async fn set_quiet_hours(
+        &self,
+        ChatId(chat_id): ChatId,
+        quiet: Option<QuietHours>,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.set_quiet_hours;
+
+        let (start, end, mode) = match quiet {
+            Some(QuietHours { start, end, mode }) => (Some(start), Some(end), mode),
+            None => (None, None, QuietMode::Silent),
+        };
+
+        self.inner
+            .execute(stmt, &[&chat_id, &start, &end, &mode.as_str()])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sets the status of the chat, returns the previous one. Messages waiting
+    /// to be sent to the chat are dropped if it becomes inactive.
+    pub async fn set_chat_status(
+        &self,
+        ChatId(chat_id): ChatId,
+        status: ChatStatus,
+    ) -> Result<ChatStatus, Error> {
+        let stmt = &self.prepared.set_chat_status;
+
+        let row = self
+            .inner
+            .query_one(stmt, &[&chat_id, &status.as_str()])
+            .await?;
+
+        // Unknown statuses can only be written by hand
+        Ok(row.get::<_, &str>(0).parse().unwrap_or(ChatStatus::Active))
+    }
+
+    /// Moves subscriptions, settings and pending messages of a group which
+    /// was upgraded to a supergroup to its new id.
+    pub async fn migrate_chat(
+        &self,
+        ChatId(from): ChatId,
+        ChatId(to): ChatId,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.migrate_chat;
+
+        self.inner.execute(stmt, &[&from, &to]).await?;
+
+        Ok(())
+    }
+
+    /// Lists chats which digests (or updates held during quiet hours) should
+    /// be sent now.
+    pub async fn due_digests(&self) -> Result<impl Iterator<Item = DueDigest>, Error> {
+        let stmt = &self.prepared.due_digests;
+
+        let res = self
+            .inner
+            .query(stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| DueDigest {
+                chat_id: ChatId(row.get(0)),
+                delivery: row.get::<_, &str>(1).parse().unwrap_or(Delivery::Instant),
+                quiet: row.get(2),
+            });
+
+        Ok(res)
+    }
+
+    /// Returns queued updates of the chat (oldest first), they stay in the
+    /// queue until [`Database::finish_digest`].
+    pub async fn queued_digest(
+        &self,
+        ChatId(chat_id): ChatId,
+    ) -> Result<impl Iterator<Item = Queued>, Error> {
+        let stmt = &self.prepared.queued_digest;
+
+        let res = self
+            .inner
+            .query(stmt, &[&chat_id])
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                Some(Queued {
+                    id: row.get(0),
+                    krate: row.get(1),
+                    version: row.get(2),
+                    action: ActionKind::parse(row.get(3))?,
+                })
+            });
+
+        Ok(res)
+    }
+
+    /// Puts digest `messages` into the outbox, removes the updates they were
+    /// rendered from (`ids`) from the queue and schedules the next digest of
+    /// the chat, all in one go.
+    pub async fn finish_digest(
+        &self,
+        ChatId(chat_id): ChatId,
+        ids: &[i64],
+        messages: &[String],
+        silent: bool,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.finish_digest;
+
+        self.inner
+            .execute(stmt, &[&chat_id, &ids, &messages, &silent])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records updates found in the registry index, moving its cursor (if
+    /// it's given) at the same time.
+    pub async fn record_events(
+        &self,
+        registry: &str,
+        updates: &[Update],
+        cursor: Option<&Cursor>,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.record_events;
+
+        // Serializing index entries can't fail
+        let json = |krate| serde_json::to_string(krate).unwrap_or_default();
+        let krates: Vec<_> = updates.iter().map(|u| json(&u.krate)).collect();
+        let actions: Vec<_> = updates.iter().map(|u| u.action.as_str()).collect();
+        let prevs: Vec<_> = updates.iter().map(|u| u.prev.as_ref().map(json)).collect();
+
+        let (commit, file, state) = match cursor {
+            Some(Cursor::Commit(commit)) => (Some(commit), None, None),
+            Some(Cursor::File { krate, state }) => (None, Some(krate), state.as_ref()),
+            None => (None, None, None),
+        };
+
+        self.inner
+            .execute(
+                stmt,
+                &[&registry, &commit, &file, &state, &krates, &actions, &prevs],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns at most `limit` oldest recorded events.
+    pub async fn pending_events(&self, limit: i32) -> Result<Vec<Event>, Error> {
+        let stmt = &self.prepared.pending_events;
+
+        let res = self
+            .inner
+            .query(stmt, &[&limit])
+            .await?
+            .into_iter()
+            .map(|row| {
+                let update = (|| {
+                    let krate = serde_json::from_str(row.get(2))?;
+                    let action = ActionKind::parse(row.get(3)).ok_or("unknown action")?;
+                    let prev = row
+                        .get::<_, Option<&str>>(4)
+                        .map(serde_json::from_str)
+                        .transpose()?;
+
+                    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Update {
+                        krate,
+                        action,
+                        prev,
+                    })
+                })();
+
+                Event {
+                    id: row.get(0),
+                    registry: row.get(1),
+                    update: update.map_err(|err| err.to_string()),
+                }
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Queues everything the event turned into (for the qualified crate name
+    /// `krate`) and removes the event, at once.
+    pub async fn finish_event(
+        &self,
+        id: i64,
+        krate: &str,
+        update: &Update,
+        fanout: &Fanout,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.finish_event;
+
+        let mut texts = Vec::new();
+        let mut keyboards = Vec::new();
+        let (mut message_idx, mut chat_ids, mut silent) = (Vec::new(), Vec::new(), Vec::new());
+        for (idx, (text, keyboard, chats)) in fanout.messages.iter().enumerate() {
+            texts.push(text.as_str());
+            // Serializing markup can't fail
+            keyboards.push(
+                keyboard
+                    .as_ref()
+                    .and_then(|k| serde_json::to_string(k).ok()),
+            );
+
+            for &(ChatId(chat_id), quiet) in chats {
+                // Arrays in postgres start from 1
+                message_idx.push(idx as i32 + 1);
+                chat_ids.push(chat_id);
+                silent.push(quiet);
+            }
+        }
+
+        let ids = |chats: &[ChatId]| chats.iter().map(|&ChatId(id)| id).collect::<Vec<_>>();
+        let post = fanout.post.as_ref();
+
+        self.inner
+            .execute(
+                stmt,
+                &[
+                    &id,
+                    &krate,
+                    &update.krate.id.vers,
+                    &update.action.as_str(),
+                    &texts,
+                    &keyboards,
+                    &message_idx,
+                    &chat_ids,
+                    &silent,
+                    &ids(&fanout.digest),
+                    &ids(&fanout.held),
+                    &fanout.delete_crate,
+                    &post.map(|post| post.chat_id.0),
+                    &post.and_then(|post| post.id),
+                    &post.map(|post| &post.group_key),
+                    &post.map(|post| &post.lines),
+                    &post.map(|post| &post.text),
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes the event without delivering it.
+    pub async fn remove_event(&self, id: i64) -> Result<(), Error> {
+        let stmt = &self.prepared.remove_event;
+
+        self.inner.execute(stmt, &[&id]).await?;
+
+        Ok(())
+    }
+
+    /// Returns at most `limit` oldest messages which can be sent now.
+    pub async fn outbox_batch(&self, limit: i32) -> Result<Vec<Outgoing>, Error> {
+        let stmt = &self.prepared.outbox_batch;
+
+        let res = self
+            .inner
+            .query(stmt, &[&limit])
+            .await?
+            .into_iter()
+            .map(|row| Outgoing {
+                id: row.get(0),
+                chat_id: ChatId(row.get(1)),
+                text: row.get(2),
+                keyboard: row
+                    .get::<_, Option<&str>>(3)
+                    .and_then(|keyboard| serde_json::from_str(keyboard).ok()),
+                silent: row.get(4),
+                attempts: row.get(5),
+                post: row
+                    .get::<_, Option<i64>>(6)
+                    .map(|post_id| (post_id, row.get::<_, Option<i32>>(7).map(MessageId))),
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Removes the message from the outbox (after it was sent, or if it can't
+    /// be sent at all).
+    pub async fn remove_outgoing(&self, id: i64) -> Result<(), Error> {
+        let stmt = &self.prepared.remove_outgoing;
+
+        self.inner.execute(stmt, &[&id]).await?;
+
+        Ok(())
+    }
+
+    /// Postpones the message (after a failed attempt to send it), later
+    /// messages to the same chat are postponed too.
+    pub async fn postpone_outgoing(&self, id: i64, delay: Duration) -> Result<(), Error> {
+        let stmt = &self.prepared.postpone_outgoing;
+
+        self.inner
+            .execute(stmt, &[&id, &delay.as_secs_f64()])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the id and lines of the channel post which collects updates
+    /// with `group_key` in the chat, if it was started less than `window` ago.
+    pub async fn open_post(
+        &self,
+        chat_id: ChatId,
+        group_key: &str,
+        window: Duration,
+    ) -> Result<Option<(i64, Vec<String>)>, Error> {
+        let stmt = &self.prepared.open_post;
+
+        let res = self
+            .inner
+            .query_opt(stmt, &[&chat_id.0, &group_key, &window.as_secs_f64()])
+            .await?
+            .map(|row| (row.get(0), row.get(1)));
+
+        Ok(res)
+    }
+
+    /// Remembers the message the channel post was sent as, so that it's edited
+    /// afterwards.
+    pub async fn set_post_message(&self, post_id: i64, message_id: MessageId) -> Result<(), Error> {
+        let stmt = &self.prepared.set_post_message;
+
+        self.inner.execute(stmt, &[&post_id, &message_id.0]).await?;
+
+        Ok(())
+    }
+
+    /// Sets the filter of the subscription. Returns `false` if the chat is not
+    /// subscribed to the crate.
+    pub async fn set_filter(
+        &self,
+        ChatId(chat_id): ChatId,
+        krate: &str,
+        filter: Filter,
+    ) -> Result<bool, Error> {
+        let stmt = &self.prepared.set_filter;
+
+        let Filter {
+            new_versions,
+            yanks,
+            unyanks,
+            prereleases,
+        } = filter;
+
+        let row = self
+            .inner
+            .query_one(
+                stmt,
+                &[
+                    &chat_id,
+                    &krate,
+                    &new_versions,
+                    &yanks,
+                    &unyanks,
+                    &prereleases,
+                ],
+            )
+            .await?;
+
+        Ok(row.get(0))
+    }
+}
+
+/// Chat subscribed to some crate.
+pub struct Subscriber {
+    pub chat_id: ChatId,
+    /// Version requirement, versions that don't match it should not be
+    /// reported (`None` means "any version")
+    pub req: Option<VersionReq>,
+    pub filter: Filter,
+    /// Whether the subscription is temporarily muted
+    pub muted: bool,
+    /// Whether updates should be queued for a digest instead of being sent
+    /// right away
+    pub digest: bool,
+    /// What to do with updates if the chat is in quiet hours right now
+    pub quiet: Option<QuietMode>,
+}
+
+impl Subscriber {
+    /// Returns `true` if the subscriber is interested in `action` happening
+    /// to version `vers`.
+    pub fn wants(&self, vers: &str, action: &ActionKind) -> bool {
+        if !self.filter.allows(vers, action) {
+            return false;
+        }
+
+        // All versions are gone, so the requirement doesn't matter (and it's
+        // too important to be muted)
+        if let ActionKind::Deleted = action {
+            return true;
+        }
+
+        if self.muted {
+            return false;
+        }
+
+        match (&self.req, vers.parse()) {
+            (Some(req), Ok(vers)) => req.matches(&vers),
+            // Better notify than silently drop an update
+            (Some(_), Err(_)) | (None, _) => true,
+        }
+    }
+
+    /// Returns how an update should reach the subscriber.
+    pub fn route(&self) -> Route {
+        match self.quiet {
+            // Digests collect updates regardless of quiet hours
+            _ if self.digest => Route::Digest,
+            Some(QuietMode::Hold) => Route::Held,
+            quiet => Route::Send {
+                silent: quiet == Some(QuietMode::Silent),
+            },
+        }
+    }
+}
+
+/// How an update reaches a subscriber, see [`Subscriber::route`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// The update is sent with the next digest of the chat
+    Digest,
+    /// The update is sent when quiet hours of the chat end
+    Held,
+    /// The update is sent right away (silently during quiet hours)
+    Send { silent: bool },
+}
+
+/// Crate to which some chat is subscribed.
+pub struct Subscription {
+    pub krate: String,
+    pub req: Option<VersionReq>,
+    pub filter: Filter,
+}
+
+/// Update recorded in the db, waiting to be turned into notifications.
+pub struct Event {
+    pub id: i64,
+    /// Name of the registry the update is from
+    pub registry: String,
+    /// The update, or an error if it couldn't be deserialized
+    pub update: Result<Update, String>,
+}
+
+/// What an update turns into, see [`Database::finish_event`].
+#[derive(Default)]
+pub struct Fanout {
+    /// Messages and chats they should be sent to (with a flag telling whether
+    /// to send them silently)
+    pub messages: Vec<(String, Option<InlineKeyboardMarkup>, Vec<(ChatId, bool)>)>,
+    /// Chats which get the update with their next digest
+    pub digest: Vec<ChatId>,
+    /// Chats which get the update when their quiet hours end
+    pub held: Vec<ChatId>,
+    /// Whether the crate should be deleted (after a deletion was reported)
+    pub delete_crate: bool,
+    /// Channel post the update is added to (if channel posts are aggregated)
+    pub post: Option<ChannelPost>,
+}
+
+/// Channel post which collects several updates.
+pub struct ChannelPost {
+    pub chat_id: ChatId,
+    /// `None` if a new post is started
+    pub id: Option<i64>,
+    /// Updates with the same key are collected into the same post
+    pub group_key: String,
+    /// Html lines of the post, one per update
+    pub lines: Vec<String>,
+    /// Html text of the post
+    pub text: String,
+}
+
+/// Message waiting in the outbox.
+pub struct Outgoing {
+    pub id: i64,
+    pub chat_id: ChatId,
+    /// Html text of the message
+    pub text: String,
+    pub keyboard: Option<InlineKeyboardMarkup>,
+    /// Whether the message should be sent without a sound
+    pub silent: bool,
+    /// Number of failed attempts to send the message
+    pub attempts: i32,
+    /// Channel post the message is a version of, and the message it was
+    /// already sent as (then it's edited instead)
+    pub post: Option<(i64, Option<MessageId>)>,
+}
+
+/// Update queued for a digest.
+pub struct Queued {
+    pub id: i64,
+    pub krate: String,
+    pub version: String,
+    pub action: ActionKind,
+}
+
+/// Chat which has a digest to be sent.
+pub struct DueDigest {
+    pub chat_id: ChatId,
+    /// `Delivery::Instant` if these are updates held during quiet hours (or
+    /// queued before digests were turned off)
+    pub delivery: Delivery,
+    /// Whether the chat is in quiet hours right now
+    pub quiet: bool,
+}
+
+/// Hour at which digests are sent by default.
+pub const DEFAULT_DIGEST_HOUR: i32 = 9;
+
+/// Per-chat settings.
+pub struct Settings {
+    pub delivery: Delivery,
+    /// Hour (in `timezone`) at which digests are sent
+    pub digest_hour: i32,
+    /// Name of the timezone, e.g. `Europe/Berlin`
+    pub timezone: String,
+    pub quiet: Option<QuietHours>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            delivery: Delivery::Instant,
+            digest_hour: DEFAULT_DIGEST_HOUR,
+            timezone: String::from("UTC"),
+            quiet: None,
+        }
+    }
+}
+
+/// Hours (in the chat timezone) during which updates are sent silently or
+/// held. The window may wrap around midnight (e.g. from 22 to 8).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: i32,
+    pub end: i32,
+    pub mode: QuietMode,
+}
+
+/// What happens to updates during quiet hours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuietMode {
+    /// Send them without a sound
+    Silent,
+    /// Send them when quiet hours end
+    Hold,
+}
+
+impl QuietMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            QuietMode::Silent => "silent",
+            QuietMode::Hold => "hold",
+        }
+    }
+}
+
+/// Parses `silent` or `hold`.
+impl FromStr for QuietMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "silent" => Ok(QuietMode::Silent),
+            "hold" => Ok(QuietMode::Hold),
+            unknown => Err(format!("unknown quiet hours mode `{unknown}`")),
+        }
+    }
+}
+
+/// How updates are delivered to a chat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// A message per update, as soon as it's found
+    Instant,
+    /// All updates of a day in a single message
+    Daily,
+    /// All updates of a week in a single message, sent on mondays
+    Weekly,
+}
+
+impl Delivery {
+    fn as_str(self) -> &'static str {
+        match self {
+            Delivery::Instant => "instant",
+            Delivery::Daily => "daily",
+            Delivery::Weekly => "weekly",
+        }
+    }
+}
+
+/// Parses `instant` (or `off`), `daily` or `weekly`.
+impl FromStr for Delivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "instant" | "off" => Ok(Delivery::Instant),
+            "daily" => Ok(Delivery::Daily),
+            "weekly" => Ok(Delivery::Weekly),
+            unknown => Err(format!("unknown delivery mode `{unknown}`")),
+        }
+    }
+}
+
+/// Whether the bot can send messages to a chat. Inactive chats keep their
+/// subscriptions, but aren't notified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatStatus {
+    Active,
+    /// The user blocked the bot
+    Blocked,
+    /// The bot was removed from the group or channel
+    Kicked,
+    /// The group was upgraded to a supergroup (which has another id)
+    Migrated,
+}
+
+impl ChatStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChatStatus::Active => "active",
+            ChatStatus::Blocked => "blocked",
+            ChatStatus::Kicked => "kicked",
+            ChatStatus::Migrated => "migrated",
+        }
+    }
+}
+
+impl FromStr for ChatStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(ChatStatus::Active),
+            "blocked" => Ok(ChatStatus::Blocked),
+            "kicked" => Ok(ChatStatus::Kicked),
+            "migrated" => Ok(ChatStatus::Migrated),
+            unknown => Err(format!("unknown chat status `{unknown}`")),
+        }
+    }
+}
+
+/// Numbers of subscriptions changed by [`Database::replace_lockfile`].
+pub struct LockfileChanges {
+    pub added: i64,
+    pub updated: i64,
+    pub removed: i64,
+}
+
+/// Kinds of updates a subscriber wants to be notified about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub new_versions: bool,
+    pub yanks: bool,
+    pub unyanks: bool,
+    /// Whether pre-release versions (e.g. `1.0.0-rc.1`) are reported
+    pub prereleases: bool,
+}
+
+impl Filter {
+    pub const ALL: Self = Self {
+        new_versions: true,
+        yanks: true,
+        unyanks: true,
+        prereleases: true,
+    };
+
+    pub const NONE: Self = Self {
+        new_versions: false,
+        yanks: false,
+        unyanks: false,
+        prereleases: false,
+    };
+
+    pub fn allows(&self, vers: &str, action: &ActionKind) -> bool {
+        let kind = match action {
+            ActionKind::NewVersion => self.new_versions,
+            ActionKind::Yanked | ActionKind::VersionRemoved => self.yanks,
+            ActionKind::Unyanked => self.unyanks,
+            // Crate deletion is too important to be filtered out
+            ActionKind::Deleted => return true,
+        };
+
+        // Build metadata may contain `-` too, so strip it first
+        let prerelease = vers.split('+').next().unwrap_or(vers).contains('-');
+
+        kind && (self.prereleases || !prerelease)
+    }
+
+    fn from_row(row: &tokio_postgres::Row, start: usize) -> Self {
+        Self {
+            new_versions: row.get(start),
+            yanks: row.get(start + 1),
+            unyanks: row.get(start + 2),
+            prereleases: row.get(start + 3),
+        }
+    }
+}
+
+/// Formats the filter the same way it's accepted by `/filter`.
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kinds = [
+            (self.new_versions, "new"),
+            (self.yanks, "yanked"),
+            (self.unyanks, "unyanked"),
+            (self.prereleases, "pre"),
+        ];
+
+        let mut first = true;
+        for (_, name) in kinds.iter().filter(|(on, _)| *on) {
+            if !first {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses space- or comma-separated list of `new`, `yanked`, `unyanked`, `pre`
+/// (or just `all`).
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::NONE;
+
+        for kind in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            match kind {
+                "" => {}
+                "all" => filter = Self::ALL,
+                "new" => filter.new_versions = true,
+                "yanked" => filter.yanks = true,
+                "unyanked" => filter.unyanks = true,
+                "pre" => filter.prereleases = true,
+                unknown => return Err(format!("unknown update kind `{unknown}`")),
+            }
+        }
+
+        if !(filter.new_versions || filter.yanks || filter.unyanks) {
+            return Err(String::from(
+                "at least one of `new`, `yanked`, `unyanked` is required",
+            ));
+        }
+
+        Ok(filter)
+    }
+}
+
+fn parse_req(req: Option<&str>) -> Option<VersionReq> {
+    // Requirements are validated before inserting them into the db, so this
+    // shouldn't fail
+    req.and_then(|req| req.parse().ok())
 }
 
 struct Prepared {
@@ -87,6 +1092,35 @@ struct Prepared {
     unsubscribe: Statement,
     list_subscribers: Statement,
     list_subscriptions: Statement,
+    set_filter: Statement,
+    list_crates: Statement,
+    get_cursor: Statement,
+    sparse_files: Statement,
+    set_cursor: Statement,
+    replace_lockfile: Statement,
+    watch: Statement,
+    unwatch: Statement,
+    list_watchers: Statement,
+    mute: Statement,
+    unmute: Statement,
+    get_settings: Statement,
+    set_delivery: Statement,
+    set_timezone: Statement,
+    set_quiet_hours: Statement,
+    set_chat_status: Statement,
+    migrate_chat: Statement,
+    record_events: Statement,
+    pending_events: Statement,
+    finish_event: Statement,
+    remove_event: Statement,
+    outbox_batch: Statement,
+    remove_outgoing: Statement,
+    postpone_outgoing: Statement,
+    open_post: Statement,
+    set_post_message: Statement,
+    due_digests: Statement,
+    queued_digest: Statement,
+    finish_digest: Statement,
 }
 
 impl Prepared {
@@ -96,7 +1130,10 @@ impl Prepared {
     ) -> Result<Self, Error> {
         let prepare = async {
             let subscribe = client
-                .prepare_typed("CALL subscribe($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .prepare_typed(
+                    "CALL subscribe($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+                )
                 .await?;
 
             let unsubscribe = client
@@ -104,21 +1141,275 @@ impl Prepared {
                 .await?;
 
             let list_subscribers = client
-                .prepare_typed("SELECT user_id from list_subscribers($1)", &[Type::VARCHAR])
+                .prepare_typed(
+                    "SELECT user_id, req, new_versions, yanks, unyanks, prereleases, muted, \
+                     digest, quiet from list_subscribers($1)",
+                    &[Type::VARCHAR],
+                )
                 .await?;
 
             let list_subscriptions = client
                 .prepare_typed(
-                    "SELECT crate_name from list_subscriptions($1)",
+                    "SELECT crate_name, req, new_versions, yanks, unyanks, prereleases from \
+                     list_subscriptions($1)",
                     &[Type::INT8],
                 )
                 .await?;
 
+            let set_filter = client
+                .prepare_typed(
+                    "SELECT set_filter($1, $2, $3, $4, $5, $6)",
+                    &[
+                        Type::INT8,
+                        Type::VARCHAR,
+                        Type::BOOL,
+                        Type::BOOL,
+                        Type::BOOL,
+                        Type::BOOL,
+                    ],
+                )
+                .await?;
+
+            let list_crates = client
+                .prepare_typed("SELECT crate_name from list_crates()", &[])
+                .await?;
+
+            let get_cursor = client
+                .prepare_typed(
+                    "SELECT commit_oid from index_cursors where registry = $1",
+                    &[Type::VARCHAR],
+                )
+                .await?;
+
+            let sparse_files = client
+                .prepare_typed(
+                    "SELECT crate_name, state from sparse_files where registry = $1",
+                    &[Type::VARCHAR],
+                )
+                .await?;
+
+            let set_cursor = client
+                .prepare_typed("CALL set_cursor($1, $2)", &[Type::VARCHAR, Type::VARCHAR])
+                .await?;
+
+            let replace_lockfile = client
+                .prepare_typed(
+                    "SELECT added, updated, removed from replace_lockfile($1, $2, $3, $4, $5)",
+                    &[
+                        Type::INT8,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let watch = client
+                .prepare_typed(
+                    "CALL watch($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+                )
+                .await?;
+
+            let unwatch = client
+                .prepare_typed(
+                    "SELECT unwatch($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+                )
+                .await?;
+
+            let list_watchers = client
+                .prepare_typed(
+                    "SELECT user_id from list_watchers($1, $2)",
+                    &[Type::VARCHAR, Type::VARCHAR],
+                )
+                .await?;
+
+            let mute = client
+                .prepare_typed(
+                    "SELECT mute($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::INT4],
+                )
+                .await?;
+
+            let unmute = client
+                .prepare_typed("SELECT unmute($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let get_settings = client
+                .prepare_typed(
+                    "SELECT delivery, digest_hour, timezone, quiet_start, quiet_end, quiet_mode \
+                     from chats where user_id = $1",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let set_delivery = client
+                .prepare_typed(
+                    "CALL set_delivery($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::INT4],
+                )
+                .await?;
+
+            let set_chat_status = client
+                .prepare_typed(
+                    "SELECT set_chat_status($1, $2)",
+                    &[Type::INT8, Type::VARCHAR],
+                )
+                .await?;
+
+            let migrate_chat = client
+                .prepare_typed("CALL migrate_chat($1, $2)", &[Type::INT8, Type::INT8])
+                .await?;
+
+            let set_timezone = client
+                .prepare_typed("SELECT set_timezone($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let set_quiet_hours = client
+                .prepare_typed(
+                    "CALL set_quiet_hours($1, $2, $3, $4)",
+                    &[Type::INT8, Type::INT4, Type::INT4, Type::VARCHAR],
+                )
+                .await?;
+
+            let record_events = client
+                .prepare_typed(
+                    "CALL record_events($1, $2, $3, $4, $5, $6, $7)",
+                    &[
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let pending_events = client
+                .prepare_typed(
+                    "SELECT id, registry, krate, action, prev from events order by id limit $1",
+                    &[Type::INT4],
+                )
+                .await?;
+
+            let finish_event = client
+                .prepare_typed(
+                    "CALL finish_event($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, \
+                     $14, $15, $16, $17)",
+                    &[
+                        Type::INT8,
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::INT4_ARRAY,
+                        Type::INT8_ARRAY,
+                        Type::BOOL_ARRAY,
+                        Type::INT8_ARRAY,
+                        Type::INT8_ARRAY,
+                        Type::BOOL,
+                        Type::INT8,
+                        Type::INT8,
+                        Type::VARCHAR,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR,
+                    ],
+                )
+                .await?;
+
+            let remove_event = client
+                .prepare_typed("DELETE from events where id = $1", &[Type::INT8])
+                .await?;
+
+            let outbox_batch = client
+                .prepare_typed(
+                    "SELECT id, chat_id, text, keyboard, silent, attempts, post_id, message_id \
+                     from outbox_batch($1)",
+                    &[Type::INT4],
+                )
+                .await?;
+
+            let open_post = client
+                .prepare_typed(
+                    "SELECT id, lines from open_post($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::FLOAT8],
+                )
+                .await?;
+
+            let set_post_message = client
+                .prepare_typed("CALL set_post_message($1, $2)", &[Type::INT8, Type::INT4])
+                .await?;
+
+            let remove_outgoing = client
+                .prepare_typed("DELETE from outbox where id = $1", &[Type::INT8])
+                .await?;
+
+            let postpone_outgoing = client
+                .prepare_typed("CALL postpone_outbox($1, $2)", &[Type::INT8, Type::FLOAT8])
+                .await?;
+
+            let due_digests = client
+                .prepare_typed("SELECT user_id, delivery, quiet from due_digests()", &[])
+                .await?;
+
+            let queued_digest = client
+                .prepare_typed(
+                    "SELECT id, crate_name, version, action from queued_digest($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let finish_digest = client
+                .prepare_typed(
+                    "CALL finish_digest($1, $2, $3, $4)",
+                    &[
+                        Type::INT8,
+                        Type::INT8_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::BOOL,
+                    ],
+                )
+                .await?;
+
             Ok(Self {
                 subscribe,
                 unsubscribe,
                 list_subscribers,
                 list_subscriptions,
+                set_filter,
+                list_crates,
+                get_cursor,
+                sparse_files,
+                set_cursor,
+                replace_lockfile,
+                watch,
+                unwatch,
+                list_watchers,
+                mute,
+                unmute,
+                get_settings,
+                set_delivery,
+                set_timezone,
+                set_quiet_hours,
+                set_chat_status,
+                migrate_chat,
+                record_events,
+                pending_events,
+                finish_event,
+                remove_event,
+                outbox_batch,
+                remove_outgoing,
+                postpone_outgoing,
+                open_post,
+                set_post_message,
+                due_digests,
+                queued_digest,
+                finish_digest,
             })
         };
 
@@ -131,3 +1422,69 @@ impl Prepared {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::ChatId;
+
+    use super::{Filter, QuietMode, Route, Subscriber};
+
+    #[test]
+    fn filter_roundtrip() {
+        for input in [
+            "new",
+            "yanked",
+            "unyanked",
+            "new pre",
+            "new yanked",
+            "yanked unyanked",
+            "new yanked unyanked",
+            "new yanked unyanked pre",
+        ] {
+            let filter: Filter = input.parse().unwrap();
+            assert_eq!(filter.to_string(), input);
+        }
+
+        // Other spellings of the same filters
+        let parse = |s: &str| s.parse::<Filter>().unwrap().to_string();
+        assert_eq!(parse("all"), "new yanked unyanked pre");
+        assert_eq!(parse("yanked,new"), "new yanked");
+        assert_eq!(parse(" pre,  new "), "new pre");
+        assert_eq!(Filter::ALL.to_string(), "new yanked unyanked pre");
+    }
+
+    #[test]
+    fn invalid_filter() {
+        for input in ["", "pre", "new removed", "New", "all,nope", ",,"] {
+            assert!(input.parse::<Filter>().is_err(), "{:?} was accepted", input);
+        }
+    }
+
+    fn subscriber(digest: bool, quiet: Option<QuietMode>) -> Subscriber {
+        Subscriber {
+            chat_id: ChatId(1),
+            req: None,
+            filter: Filter::ALL,
+            muted: false,
+            digest,
+            quiet,
+        }
+    }
+
+    #[test]
+    fn route() {
+        let route = |digest, quiet| subscriber(digest, quiet).route();
+
+        assert_eq!(route(false, None), Route::Send { silent: false });
+        assert_eq!(
+            route(false, Some(QuietMode::Silent)),
+            Route::Send { silent: true }
+        );
+        assert_eq!(route(false, Some(QuietMode::Hold)), Route::Held);
+
+        // Digests are sent later anyway
+        assert_eq!(route(true, None), Route::Digest);
+        assert_eq!(route(true, Some(QuietMode::Silent)), Route::Digest);
+        assert_eq!(route(true, Some(QuietMode::Hold)), Route::Digest);
+    }
+}
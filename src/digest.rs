@@ -0,0 +1,211 @@
+//! Digests, i.e. updates queued for chats which don't want to be notified
+//! about every update right away.
+use std::time::Duration;
+
+use futures::Future;
+
+use crate::{
+    db::{Database, Delivery, DueDigest, Queued},
+    index::ActionKind,
+};
+
+/// How often the database is checked for due digests.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum length of a digest message (telegram limit is 4096 characters),
+/// longer digests are split.
+const MAX_MESSAGE_LEN: usize = 4000;
+
+/// Sends due digests until `stop` resolves.
+pub async fn run(mut stop: impl Future + Unpin, db: &Database) {
+    loop {
+        match db.due_digests().await {
+            Ok(chats) => {
+                for due in chats {
+                    send(due, db).await;
+                }
+            }
+            Err(err) => log::error!("db error while getting due digests: {}", err),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = &mut stop => break,
+        }
+    }
+}
+
+async fn send(due: DueDigest, db: &Database) {
+    let DueDigest {
+        chat_id,
+        delivery,
+        quiet,
+    } = due;
+
+    let queued: Vec<_> = match db.queued_digest(chat_id).await {
+        Ok(queued) => queued.collect(),
+        Err(err) => {
+            log::error!("db error while getting digest of {}: {}", chat_id, err);
+            return;
+        }
+    };
+
+    let title = match delivery {
+        Delivery::Daily => "📰 <b>Daily digest</b>",
+        Delivery::Weekly => "📰 <b>Weekly digest</b>",
+        // Updates held during quiet hours
+        Delivery::Instant => "📬 <b>Delayed updates</b>",
+    };
+
+    // Nothing happened since the last digest, it's only rescheduled then
+    let messages = if queued.is_empty() {
+        Vec::new()
+    } else {
+        render(title, &queued)
+    };
+    let ids: Vec<_> = queued.iter().map(|q| q.id).collect();
+
+    // If this fails the updates stay queued and the digest is retried later
+    db.finish_digest(chat_id, &ids, &messages, quiet)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("db error while queueing digest of {}: {}", chat_id, err)
+        });
+}
+
+/// Renders the digest, updates are grouped by crate and then by action.
+/// Returns several messages if the digest doesn't fit into one.
+fn render(title: &str, queued: &[Queued]) -> Vec<String> {
+    // Crates in order of their first update
+    let mut crates: Vec<(&str, Vec<(&ActionKind, Vec<&str>)>)> = Vec::new();
+    for Queued {
+        krate,
+        version,
+        action,
+        ..
+    } in queued
+    {
+        let actions = match crates.iter().position(|(name, _)| name == krate) {
+            Some(idx) => &mut crates[idx].1,
+            None => {
+                crates.push((krate, Vec::new()));
+                &mut crates.last_mut().unwrap().1
+            }
+        };
+
+        match actions.iter_mut().find(|(a, _)| *a == action) {
+            Some((_, versions)) => versions.push(version),
+            None => actions.push((action, vec![version])),
+        }
+    }
+
+    let mut messages = vec![format!(
+        "{title}: {} updates of {} crates\n",
+        queued.len(),
+        crates.len()
+    )];
+
+    for (krate, actions) in crates {
+        let actions: Vec<_> = actions
+            .iter()
+            .map(|(action, versions)| {
+                let action = match action {
+                    ActionKind::NewVersion => "released",
+                    ActionKind::Yanked => "yanked",
+                    ActionKind::Unyanked => "unyanked",
+                    ActionKind::VersionRemoved => "removed",
+                    // There are no versions left to list
+                    ActionKind::Deleted => return String::from("the crate was deleted"),
+                };
+
+                format!("{action} <code>{}</code>", versions.join("</code>, <code>"))
+            })
+            .collect();
+
+        let line = format!("<code>{krate}</code>: {}", actions.join("; "));
+
+        let last = messages
+            .last_mut()
+            .expect("there is always at least one message");
+        if last.len() + line.len() + 1 > MAX_MESSAGE_LEN {
+            messages.push(line);
+        } else {
+            last.push('\n');
+            last.push_str(&line);
+        }
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, MAX_MESSAGE_LEN};
+    use crate::{db::Queued, index::ActionKind};
+
+    fn queued(krate: &str, version: &str, action: ActionKind) -> Queued {
+        Queued {
+            id: 0,
+            krate: krate.to_owned(),
+            version: version.to_owned(),
+            action,
+        }
+    }
+
+    #[test]
+    fn grouped() {
+        let digest = render(
+            "📰 <b>Daily digest</b>",
+            &[
+                queued("tokio", "1.28.0", ActionKind::NewVersion),
+                queued("serde", "1.0.160", ActionKind::Yanked),
+                queued("tokio", "1.28.1", ActionKind::NewVersion),
+                queued("tokio", "1.27.0", ActionKind::Yanked),
+                queued("serde", "1.0.160", ActionKind::Unyanked),
+                queued("left-pad", "0.1.0", ActionKind::VersionRemoved),
+                queued("left-pad", "0.1.1", ActionKind::Deleted),
+            ],
+        );
+
+        assert_eq!(
+            digest,
+            [concat!(
+                "📰 <b>Daily digest</b>: 7 updates of 3 crates\n",
+                "\n",
+                "<code>tokio</code>: released <code>1.28.0</code>, <code>1.28.1</code>; ",
+                "yanked <code>1.27.0</code>\n",
+                "<code>serde</code>: yanked <code>1.0.160</code>; unyanked <code>1.0.160</code>\n",
+                "<code>left-pad</code>: removed <code>0.1.0</code>; the crate was deleted",
+            )]
+        );
+    }
+
+    #[test]
+    fn split() {
+        let queued: Vec<_> = (0..300)
+            .map(|n| queued(&format!("crate-{n:03}"), "1.0.0", ActionKind::NewVersion))
+            .collect();
+        let digest = render("📰 <b>Weekly digest</b>", &queued);
+
+        assert!(digest.len() > 1);
+        assert!(digest[0].starts_with("📰 <b>Weekly digest</b>: 300 updates of 300 crates\n"));
+        assert!(digest.iter().all(|text| text.len() <= MAX_MESSAGE_LEN));
+
+        // Every crate is in exactly one of the messages, in order
+        let lines: Vec<_> = digest
+            .iter()
+            .flat_map(|text| text.lines())
+            .skip(2)
+            .collect();
+        assert_eq!(lines.len(), 300);
+        assert_eq!(
+            lines[0],
+            "<code>crate-000</code>: released <code>1.0.0</code>"
+        );
+        assert_eq!(
+            lines[299],
+            "<code>crate-299</code>: released <code>1.0.0</code>"
+        );
+        assert!(digest[1].starts_with("<code>crate-"));
+    }
+}
@@ -0,0 +1,274 @@
+//! Sources of index updates.
+use crate::krate::Crate;
+
+pub mod git;
+pub mod sparse;
+
+/// Update of a crate (e.g. new version) and the crate version it affects.
+#[derive(Debug)]
+pub struct Update {
+    pub krate: Crate,
+    pub action: ActionKind,
+    /// The version preceding `krate` in the index file, if it's known. Only
+    /// set for new versions.
+    pub prev: Option<Crate>,
+}
+
+impl Update {
+    pub fn new(krate: Crate, action: ActionKind) -> Self {
+        Self {
+            krate,
+            action,
+            prev: None,
+        }
+    }
+}
+
+/// Updates from the index and the position in the index after them.
+pub struct Batch {
+    pub updates: Vec<Update>,
+    /// Position in the index after the updates, it's stored together with the
+    /// updates, so that they are neither lost nor recorded twice. `None` if the
+    /// source doesn't track its position.
+    pub cursor: Option<Cursor>,
+}
+
+/// Position in the index, see [`Batch::cursor`].
+pub enum Cursor {
+    /// The last processed commit of a git index
+    Commit(String),
+    /// The last seen state of a crate file of a sparse index (serialized as
+    /// json), `None` if the crate is gone
+    File {
+        krate: String,
+        state: Option<String>,
+    },
+}
+
+/// Source of index updates (e.g. git or sparse index).
+#[async_trait::async_trait]
+pub trait IndexSource: Send {
+    /// Get the next batch of updates, waiting until there are some.
+    ///
+    /// Sources don't wait until batches are processed, they move on as soon as
+    /// a batch is taken. Returns `None` when there won't be any more updates.
+    async fn next(&mut self) -> Option<Result<Batch, Error>>;
+}
+
+#[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
+pub enum Error {
+    Git(git2::Error),
+    Sparse(sparse::Error),
+    Db(tokio_postgres::Error),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ActionKind {
+    NewVersion,
+    Yanked,
+    Unyanked,
+    /// A version was removed from the index (e.g. because it contained
+    /// malware)
+    VersionRemoved,
+    /// The whole crate was removed from the index
+    Deleted,
+}
+
+impl ActionKind {
+    /// Name of the action as it's stored in the db.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActionKind::NewVersion => "new",
+            ActionKind::Yanked => "yanked",
+            ActionKind::Unyanked => "unyanked",
+            ActionKind::VersionRemoved => "removed",
+            ActionKind::Deleted => "deleted",
+        }
+    }
+
+    /// Reverse of [`ActionKind::as_str`].
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "new" => Some(ActionKind::NewVersion),
+            "yanked" => Some(ActionKind::Yanked),
+            "unyanked" => Some(ActionKind::Unyanked),
+            "removed" => Some(ActionKind::VersionRemoved),
+            "deleted" => Some(ActionKind::Deleted),
+            _ => None,
+        }
+    }
+}
+
+/// Changes of a single crate file in the index.
+#[derive(Default)]
+struct FileDiff {
+    deleted: bool,
+    removed: Vec<Crate>,
+    added: Vec<Crate>,
+    /// All lines of the file after the change, used to find versions preceding
+    /// the added ones. May be empty if unknown.
+    file: Vec<Crate>,
+}
+
+impl FileDiff {
+    /// Pair removed and added lines by version and turn them into updates.
+    ///
+    /// `origin` describes where the diff came from, it's used for logging.
+    fn into_updates(self, origin: &str) -> Vec<Update> {
+        let Self {
+            deleted,
+            mut removed,
+            added,
+            file,
+        } = self;
+
+        if deleted {
+            // All lines of the file are deleted, they are different versions of the same
+            // crate, keep the last (i.e. the latest) one
+            return removed
+                .pop()
+                .map(|krate| Update::new(krate, ActionKind::Deleted))
+                .into_iter()
+                .collect();
+        }
+
+        let mut updates = Vec::new();
+        for next in added {
+            let prev = removed
+                .iter()
+                .position(|prev| prev.id.vers == next.id.vers)
+                .map(|idx| removed.remove(idx));
+
+            match (prev.as_ref().map(|c| c.yanked), next.yanked) {
+                /* was yanked?, is yanked? */
+                (None, false) => {
+                    // There were no deleted line & crate is not yanked.
+                    // New version.
+                    let previous = file
+                        .iter()
+                        .position(|krate| krate.id == next.id)
+                        .and_then(|idx| idx.checked_sub(1))
+                        .map(|idx| file[idx].clone());
+
+                    updates.push(Update {
+                        prev: previous,
+                        ..Update::new(next, ActionKind::NewVersion)
+                    })
+                }
+                (Some(false), true) => {
+                    // The crate was not yanked and now is yanked.
+                    // Crate was yanked.
+                    updates.push(Update::new(next, ActionKind::Yanked))
+                }
+                (Some(true), false) => {
+                    // The crate was yanked and now is not yanked.
+                    // Crate was unyanked.
+                    updates.push(Update::new(next, ActionKind::Unyanked))
+                }
+                _unexpected => {
+                    // Something unexpected happened, but other updates from the
+                    // same diff may still be fine
+                    log::warn!("Unexpected diff input: {prev:?} -> {next:?} ({origin})")
+                }
+            }
+        }
+
+        // A line was removed and nothing was added instead.
+        // Version was removed.
+        updates.extend(
+            removed
+                .into_iter()
+                .map(|krate| Update::new(krate, ActionKind::VersionRemoved)),
+        );
+
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionKind, FileDiff};
+    use crate::krate::Crate;
+
+    fn krate(vers: &str, yanked: bool) -> Crate {
+        serde_json::from_str(&format!(
+            r#"{{"name":"serde","vers":"{vers}","yanked":{yanked}}}"#
+        ))
+        .unwrap()
+    }
+
+    fn summary(diff: FileDiff) -> Vec<(ActionKind, String, Option<String>)> {
+        diff.into_updates("test")
+            .into_iter()
+            .map(|u| (u.action, u.krate.id.vers, u.prev.map(|p| p.id.vers)))
+            .collect()
+    }
+
+    #[test]
+    fn several_new_versions() {
+        let diff = FileDiff {
+            added: vec![krate("1.0.1", false), krate("1.0.2", false)],
+            file: vec![
+                krate("1.0.0", false),
+                krate("1.0.1", false),
+                krate("1.0.2", false),
+            ],
+            ..FileDiff::default()
+        };
+
+        assert_eq!(
+            summary(diff),
+            [
+                (ActionKind::NewVersion, "1.0.1".into(), Some("1.0.0".into())),
+                (ActionKind::NewVersion, "1.0.2".into(), Some("1.0.1".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn yank_and_publish() {
+        let diff = FileDiff {
+            removed: vec![krate("1.0.0", false)],
+            added: vec![krate("1.0.0", true), krate("1.0.1", false)],
+            file: vec![krate("1.0.0", true), krate("1.0.1", false)],
+            ..FileDiff::default()
+        };
+
+        assert_eq!(
+            summary(diff),
+            [
+                (ActionKind::Yanked, "1.0.0".into(), None),
+                (ActionKind::NewVersion, "1.0.1".into(), Some("1.0.0".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn unyank_and_remove() {
+        let diff = FileDiff {
+            removed: vec![krate("1.0.0", true), krate("1.0.1", false)],
+            added: vec![krate("1.0.0", false)],
+            file: vec![krate("1.0.0", false)],
+            ..FileDiff::default()
+        };
+
+        assert_eq!(
+            summary(diff),
+            [
+                (ActionKind::Unyanked, "1.0.0".into(), None),
+                (ActionKind::VersionRemoved, "1.0.1".into(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn deleted() {
+        let diff = FileDiff {
+            deleted: true,
+            removed: vec![krate("1.0.0", false), krate("1.0.1", true)],
+            ..FileDiff::default()
+        };
+
+        assert_eq!(summary(diff), [(ActionKind::Deleted, "1.0.1".into(), None)]);
+    }
+}
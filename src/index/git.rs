@@ -0,0 +1,487 @@
+//! Git index backend, walks through commits of a local clone of the index.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use arraylib::Slice;
+use fntools::value::ValueExt;
+use git2::{Commit, Delta, Diff, DiffLine, DiffOptions, Oid, Repository, ResetType, Sort, Tree};
+use log::info;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::{Batch, Cursor, Error, FileDiff, IndexSource, Update};
+use crate::{cfg::Registry, db::Database, krate::Crate};
+
+/// Updates from a single commit and the commit itself.
+type Pulled = (Result<Vec<Update>, Error>, Oid);
+
+/// Index source which pulls updates from the git index in a background
+/// thread.
+///
+/// The last commit which updates were recorded is stored in the db (together
+/// with the updates), so that the bot doesn't lose its place if it's
+/// restarted or the local index is wiped or re-cloned.
+pub struct GitIndex {
+    rx: Receiver<Pulled>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GitIndex {
+    /// Open (or clone, if it doesn't exist) the local index of the registry
+    /// and start pulling updates every `pull_delay`.
+    pub async fn open(
+        registry: &Registry,
+        pull_delay: Duration,
+        db: Database,
+    ) -> Result<Self, Error> {
+        let name = registry.name.clone();
+        let repo = match Repository::open(&registry.index_path) {
+            Ok(repo) => repo,
+            Err(_) => {
+                info!("start cloning {name} index");
+                Repository::clone(&registry.index_url, &registry.index_path)?
+                    .also(|_| info!("cloning {name} index finished"))
+            }
+        };
+
+        let mut cursor = reconcile(&repo, &name, &db).await?;
+
+        let (tx, rx) = mpsc::channel(2);
+        let thread = std::thread::spawn(move || loop {
+            log::info!("start pulling updates of {name}");
+
+            if let Err(err) = pull(&repo, &tx, &mut cursor) {
+                log::error!(
+                    "couldn't pull new crate version from the {name} index: {}",
+                    err
+                )
+            }
+
+            log::info!("pulling updates of {name} finished");
+
+            // delay for `config.pull_delay` (default 5 min)
+            let mut pd = pull_delay;
+            const STEP: Duration = Duration::from_secs(5);
+
+            while pd > Duration::ZERO {
+                if tx.is_closed() {
+                    return;
+                }
+
+                pd = pd.saturating_sub(STEP);
+                std::thread::sleep(STEP);
+            }
+        });
+
+        Ok(Self {
+            rx,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Reconcile the cursor stored in the db with the state of the local index,
+/// returns the commit from which updates should be pulled.
+///
+/// The db is the source of truth, the local index is only used if there is no
+/// cursor in the db yet or if the stored commit doesn't exist in the index.
+async fn reconcile(repo: &Repository, registry: &str, db: &Database) -> Result<Oid, Error> {
+    let head = repo.head()?.peel_to_commit()?.id();
+
+    let stored = db
+        .get_cursor(registry)
+        .await?
+        .and_then(|oid| Oid::from_str(&oid).ok());
+
+    let cursor = match stored {
+        Some(oid) if repo.find_commit(oid).is_ok() => {
+            if oid != head {
+                log::info!(
+                    "{registry} index HEAD is {head}, resuming from commit#{oid} stored in the \
+                     db"
+                );
+            }
+
+            return Ok(oid);
+        }
+        Some(oid) => {
+            log::warn!(
+                "Commit#{oid} stored in the db wasn't found in the {registry} index, resuming \
+                 from HEAD ({head})"
+            );
+            head
+        }
+        None => {
+            log::info!("No cursor for {registry} index in the db, starting from HEAD ({head})");
+            head
+        }
+    };
+
+    db.set_cursor(registry, &cursor.to_string()).await?;
+
+    Ok(cursor)
+}
+
+#[async_trait::async_trait]
+impl IndexSource for GitIndex {
+    async fn next(&mut self) -> Option<Result<Batch, Error>> {
+        let (res, oid) = self.rx.recv().await?;
+
+        Some(res.map(|updates| Batch {
+            updates,
+            cursor: Some(Cursor::Commit(oid.to_string())),
+        }))
+    }
+}
+
+impl Drop for GitIndex {
+    fn drop(&mut self) {
+        // Commits which were already sent, but not taken, will be pulled again
+        // after a restart, as the cursor in the db wasn't moved past them
+        self.rx.close();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Fast-Forward (FF) to a given commit.
+///
+/// If the local branch diverged from `commit` (e.g. it still points to history
+/// which was rewritten since), it's hard reset instead: the local checkout is
+/// only a mirror and updates are tracked by the cursor.
+///
+/// Implementation is taken from <https://stackoverflow.com/a/58778350>.
+fn fast_forward(repo: &Repository, commit: &git2::Commit) -> Result<(), git2::Error> {
+    let fetch_commit = repo.find_annotated_commit(commit.id())?;
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+
+    if analysis.0.is_up_to_date() {
+        Ok(())
+    } else if analysis.0.is_fast_forward() {
+        let mut reference = repo.find_reference("refs/heads/master")?;
+        reference.set_target(fetch_commit.id(), "Fast-Forward")?;
+        repo.set_head(reference.name().unwrap())?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
+    } else {
+        log::warn!("Can't fast-forward to commit#{}, resetting", commit.id());
+        repo.reset(commit.as_object(), ResetType::Hard, None)
+    }
+}
+
+/// Pull updates starting from the `cursor` commit (which updates were already
+/// sent), `cursor` is moved forward as updates are sent.
+fn pull(repo: &Repository, ch: &Sender<Pulled>, cursor: &mut Oid) -> Result<(), git2::Error> {
+    // fetch changes from remote index
+    repo.find_remote("origin")?.fetch(&["master"], None, None)?;
+
+    let mut opts = DiffOptions::default();
+    let opts = opts.context_lines(0).minimal(true);
+
+    // The index is periodically squashed, in which case the last processed commit
+    // is not in the history of the remote anymore
+    let fetch_head = repo.find_reference("FETCH_HEAD")?.peel_to_commit()?;
+    if fetch_head.id() != *cursor && !repo.graph_descendant_of(fetch_head.id(), *cursor)? {
+        return recover(repo, ch, cursor, &fetch_head, opts);
+    }
+
+    // Collect all commits after the last processed one up to the last fetched,
+    // the last processed commit is put in front to diff the next one against
+    // (`cursor~1..FETCH_HEAD` doesn't work if the cursor is a root commit, e.g.
+    // after the index was squashed)
+    let mut walk = repo.revwalk()?;
+    walk.push(fetch_head.id())?;
+    walk.hide(*cursor)?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    let mut commits = vec![repo.find_commit(*cursor)?];
+    for oid in walk {
+        commits.push(repo.find_commit(oid?)?);
+    }
+
+    for [prev, next] in Slice::array_windows::<[_; 2]>(&commits[..]) {
+        let message = next
+            .message()
+            .unwrap_or("<invalid utf-8>")
+            .trim_end_matches('\n');
+
+        // Removals are done by crates.io team members, not by bors, but are
+        // still well-formed
+        let removal = message == "Crate version removal request" || message == "Delete crates";
+
+        // Commits from humans tend to be formatted differently, compared to
+        // machine-generated ones. This basically makes them unanalyzable.
+        if next.author().name() != Some("bors") && !removal {
+            log::warn!(
+                "Skip commit#{} from non-bors user @{}: {message}",
+                next.id(),
+                next.author().name().unwrap_or("<invalid utf-8>"),
+            );
+
+            continue;
+        }
+
+        if message.starts_with("Merge remote-tracking branch")
+            || message.starts_with("Merge branch")
+        {
+            log::warn!("Skip merge commit#{}", next.id());
+            continue;
+        }
+
+        let diff = repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&next.tree()?), Some(opts))?;
+        let res = diff_one(repo, diff, (prev, next)).map_err(Into::into);
+
+        if !send(ch, res, next.id()) {
+            return Ok(());
+        }
+
+        // 'Move' to the next commit
+        *cursor = next.id();
+        fast_forward(repo, next)?;
+    }
+
+    Ok(())
+}
+
+/// Recover from a history rewrite (squash or force push) of the remote index.
+///
+/// Updates are taken from the diff between trees of the last processed commit
+/// and the new remote head, so that updates which happened in between are
+/// neither lost nor duplicated. Then the local index is reset to the new head.
+fn recover(
+    repo: &Repository,
+    ch: &Sender<Pulled>,
+    cursor: &mut Oid,
+    fetch_head: &Commit,
+    opts: &mut DiffOptions,
+) -> Result<(), git2::Error> {
+    log::warn!(
+        "History of the index was rewritten: commit#{cursor} is not an ancestor of \
+         commit#{}, recovering",
+        fetch_head.id()
+    );
+
+    let prev = repo.find_commit(*cursor)?;
+    let diff =
+        repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&fetch_head.tree()?), Some(opts))?;
+    let res = diff_one(repo, diff, (&prev, fetch_head)).map_err(Into::into);
+
+    if !send(ch, res, fetch_head.id()) {
+        return Ok(());
+    }
+
+    *cursor = fetch_head.id();
+    repo.reset(fetch_head.as_object(), ResetType::Hard, None)?;
+
+    log::info!("Recovered from index history rewrite, now at commit#{cursor}");
+
+    Ok(())
+}
+
+/// Send updates from commit `oid` to the source, blocks only if the channel is
+/// full. Returns `false` if the source was dropped.
+fn send(ch: &Sender<Pulled>, res: Result<Vec<Update>, Error>, oid: Oid) -> bool {
+    ch.blocking_send((res, oid)).is_ok()
+}
+
+/// Get `crates.io` updates from a diff of 2 consecutive commits from a
+/// `crates.io-index` repository.
+///
+/// A commit may touch any number of crate files and lines (e.g. squashed
+/// commits or commits from mirrors which batch updates), removed and added
+/// lines are paired by version in every file.
+fn diff_one(
+    repo: &Repository,
+    diff: Diff,
+    commits: (&Commit, &Commit),
+) -> Result<Vec<Update>, git2::Error> {
+    let mut files = BTreeMap::<PathBuf, FileDiff>::new();
+
+    let mut error = Ok(());
+
+    diff.foreach(
+        &mut |_, _| true,
+        None,
+        None,
+        Some(&mut |delta, _hunk, line| {
+            let deleted = match delta.status() {
+                // New version of a crate, (un)yanked or removed old version
+                Delta::Modified | Delta::Added => false,
+                // The whole crate file was removed, i.e. the crate was deleted
+                Delta::Deleted => true,
+                delta => {
+                    log::warn!("Unexpected delta: {:?}", delta);
+                    return true;
+                }
+            };
+
+            let path = match delta.new_file().path().or_else(|| delta.old_file().path()) {
+                Some(path) => path.to_owned(),
+                None => {
+                    error = Err(format!("Unexpected delta without a path: {delta:?}"));
+                    return false;
+                }
+            };
+
+            let file = files.entry(path).or_default();
+            file.deleted = deleted;
+
+            let lines = match line.origin() {
+                '-' => &mut file.removed,
+                '+' => &mut file.added,
+                _ => return true, /* don't care */
+            };
+
+            match parse_line(&line) {
+                Ok(krate) => lines.push(krate),
+                Err(e) => {
+                    error = Err(e);
+                    return false;
+                }
+            }
+
+            true
+        }),
+    )?;
+
+    let tree = commits.1.tree()?;
+    let commits = format!("{} -> {}", commits.0.id(), commits.1.id());
+
+    if let Err(e) = error {
+        return Err(git2::Error::from_str(&format!("{e} ({commits})")));
+    }
+
+    // New versions are compared to the previous ones, which are only in the
+    // unchanged part of the file
+    for (path, file) in &mut files {
+        if !file.added.is_empty() {
+            file.file = read_file(repo, &tree, path)?;
+        }
+    }
+
+    let updates: Vec<_> = files
+        .into_values()
+        .flat_map(|file| file.into_updates(&commits))
+        .collect();
+
+    if updates.is_empty() {
+        log::warn!("No updates found in commit ({commits})");
+    }
+
+    Ok(updates)
+}
+
+/// Read all lines of the crate file at `path` in `tree`, skipping unparsable
+/// ones.
+fn read_file(repo: &Repository, tree: &Tree, path: &Path) -> Result<Vec<Crate>, git2::Error> {
+    let blob = tree.get_path(path)?.to_object(repo)?.peel_to_blob()?;
+
+    let lines = blob
+        .content()
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect();
+
+    Ok(lines)
+}
+
+/// Parse a line of a crate file from the index.
+fn parse_line(line: &DiffLine) -> Result<Crate, String> {
+    let krate = str::from_utf8(line.content()).map_err(|e| format!("Non UTF-8 diff: {e:?}"))?;
+
+    serde_json::from_str::<Crate>(krate).map_err(|e| format!("Couldn't deserialize crate: {e:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use git2::{Commit, Oid, Repository, Signature};
+    use tokio::sync::mpsc;
+
+    use super::pull;
+    use crate::index::ActionKind;
+
+    const PATH: &str = "vo/la/volatile";
+
+    /// Commits `lines` as the crate file to `master` of `repo`, on top of
+    /// `parent` (a root commit if it's `None`, i.e. a squash).
+    fn commit(repo: &Repository, parent: Option<Oid>, lines: &[&str]) -> Oid {
+        let dir = repo.workdir().unwrap();
+        fs::create_dir_all(dir.join("vo/la")).unwrap();
+        fs::write(dir.join(PATH), lines.join("\n") + "\n").unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(PATH)).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let bors = Signature::now("bors", "bors@rust-lang.com").unwrap();
+        let parent = parent.map(|oid| repo.find_commit(oid).unwrap());
+        let parents: Vec<&Commit> = parent.iter().collect();
+        let oid = repo
+            .commit(None, &bors, &bors, "Updating crate", &tree, &parents)
+            .unwrap();
+
+        repo.reference("refs/heads/master", oid, true, "test")
+            .unwrap();
+        oid
+    }
+
+    #[test]
+    fn pull_after_squash() {
+        let versions: Vec<&str> = include_str!("../../tests/fixtures/volatile")
+            .lines()
+            .collect();
+        let yanked = versions[1].replace(r#""yanked":false"#, r#""yanked":true"#);
+        let latest = versions[2].replace(r#""yanked":true"#, r#""yanked":false"#);
+
+        let dir = std::env::temp_dir().join(format!("crate-upd-bot-git-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+
+        let remote = Repository::init(dir.join("remote")).unwrap();
+        remote.set_head("refs/heads/master").unwrap();
+        let first = commit(&remote, None, &versions[..1]);
+
+        let local =
+            Repository::clone(dir.join("remote").to_str().unwrap(), dir.join("local")).unwrap();
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut cursor = first;
+        let mut pulled = |cursor: &mut Oid| {
+            pull(&local, &tx, cursor).unwrap();
+            let mut updates = Vec::new();
+            while let Ok((res, _)) = rx.try_recv() {
+                for update in res.unwrap() {
+                    updates.push((update.krate.id.vers, update.action));
+                }
+            }
+            updates
+        };
+
+        // The history is squashed into a single root commit with a new version
+        let squashed = commit(&remote, None, &versions[..2]);
+        assert_eq!(
+            pulled(&mut cursor),
+            [(String::from("0.5.3-rc.1"), ActionKind::NewVersion)]
+        );
+        assert_eq!(cursor, squashed);
+
+        // Commits on top of the (root) cursor are pulled as usual
+        let next = commit(&remote, Some(squashed), &[versions[0], &yanked]);
+        commit(&remote, Some(next), &[versions[0], &yanked, &latest]);
+        assert_eq!(
+            pulled(&mut cursor),
+            [
+                (String::from("0.5.3-rc.1"), ActionKind::Yanked),
+                (String::from("0.6.0"), ActionKind::NewVersion)
+            ]
+        );
+        assert_eq!(pulled(&mut cursor), []);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
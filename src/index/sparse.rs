@@ -0,0 +1,521 @@
+//! Sparse (HTTP) index backend.
+//!
+//! Unlike the git index, the sparse one doesn't have a feed of all changes, so
+//! only files of crates someone is subscribed to are polled. The last seen
+//! state of every file is stored in the db together with updates found in it,
+//! so that nothing is lost if the bot is restarted.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use reqwest::{
+    header::{ETAG, IF_NONE_MATCH},
+    Client, StatusCode,
+};
+
+use super::{ActionKind, Batch, Cursor, FileDiff, IndexSource, Update};
+use crate::{cfg::Registry, db::Database, krate::Crate, util::crate_path};
+
+pub struct SparseIndex {
+    client: Client,
+    url: String,
+    /// Last seen contents of crate files
+    files: HashMap<String, File>,
+}
+
+struct File {
+    etag: Option<String>,
+    versions: Vec<Crate>,
+    /// Number of polls in a row which didn't find the file
+    missing: u32,
+}
+
+/// Last seen state of a crate file, as it's stored in the db.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct State {
+    etag: Option<String>,
+    /// Index entries of all versions, as they were in the file
+    versions: Vec<Crate>,
+    missing: u32,
+}
+
+/// Number of polls in a row which must not find a crate file before the crate
+/// is reported as deleted. A single `404` may be a glitch (e.g. of the CDN),
+/// and deletions remove all subscriptions to the crate.
+const MISSING_POLLS_TO_DELETE: u32 = 3;
+
+#[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
+pub enum Error {
+    Http(reqwest::Error),
+    Json(serde_json::Error),
+}
+
+impl SparseIndex {
+    pub fn new(url: String, client: Client) -> Self {
+        Self {
+            client,
+            url,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Fetch all versions of a crate, returns `None` if there is no such crate.
+    pub async fn fetch(&self, name: &str) -> Result<Option<Vec<Crate>>, Error> {
+        match self.get(name, None).await? {
+            Response::Modified(file) => Ok(Some(file.versions)),
+            Response::NotFound | Response::NotModified => Ok(None),
+        }
+    }
+
+    /// Poll file of crate `name` and return updates since the last poll.
+    ///
+    /// The first poll of a crate only remembers its file, so it never returns
+    /// any updates.
+    pub async fn poll(&mut self, name: &str) -> Result<Vec<Update>, Error> {
+        let etag = self.files.get(name).and_then(|f| f.etag.clone());
+        let next = match self.get(name, etag.as_deref()).await? {
+            Response::NotModified => {
+                // The file is back (if it was missing)
+                if let Some(file) = self.files.get_mut(name) {
+                    file.missing = 0;
+                }
+
+                return Ok(Vec::new());
+            }
+            Response::NotFound => None,
+            Response::Modified(file) => Some(file),
+        };
+
+        let updates = match (self.files.remove(name), next) {
+            (Some(prev), Some(next)) => {
+                let updates = diff(name, &prev.versions, &next.versions);
+                self.files.insert(name.to_owned(), next);
+                updates
+            }
+            // The file is gone for long enough, i.e. the crate was deleted
+            (Some(mut prev), None) if prev.missing + 1 >= MISSING_POLLS_TO_DELETE => prev
+                .versions
+                .pop()
+                .map(|krate| Update::new(krate, ActionKind::Deleted))
+                .into_iter()
+                .collect(),
+            (Some(mut prev), None) => {
+                prev.missing += 1;
+                log::warn!(
+                    "file of crate {name} wasn't found ({} times in a row)",
+                    prev.missing
+                );
+
+                self.files.insert(name.to_owned(), prev);
+                Vec::new()
+            }
+            // The first poll of the crate
+            (None, Some(next)) => {
+                self.files.insert(name.to_owned(), next);
+                Vec::new()
+            }
+            // There is still no such crate
+            (None, None) => Vec::new(),
+        };
+
+        Ok(updates)
+    }
+
+    /// The last seen state of the file of crate `name` (serialized as json),
+    /// `None` if the file wasn't seen.
+    pub fn state(&self, name: &str) -> Option<String> {
+        let file = self.files.get(name)?;
+        let state = State {
+            etag: file.etag.clone(),
+            versions: file.versions.clone(),
+            missing: file.missing,
+        };
+
+        // Serializing the state can't fail
+        serde_json::to_string(&state).ok()
+    }
+
+    /// Restore the last seen state of the file of crate `name`, see
+    /// [`SparseIndex::state`].
+    pub fn restore(&mut self, name: &str, state: &str) -> Result<(), Error> {
+        let State {
+            etag,
+            versions,
+            missing,
+        } = serde_json::from_str(state)?;
+
+        self.files.insert(
+            name.to_owned(),
+            File {
+                etag,
+                versions,
+                missing,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Get crate file, `etag` is used to check if the file was modified.
+    async fn get(&self, name: &str, etag: Option<&str>) -> Result<Response, Error> {
+        // Sparse index uses the same layout as the git one
+        let path = crate_path(name);
+        let url = format!("{}/{}", self.url.trim_end_matches('/'), path.display());
+
+        let mut req = self.client.get(url);
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+
+        let resp = req.send().await?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => return Ok(Response::NotFound),
+            StatusCode::NOT_MODIFIED => return Ok(Response::NotModified),
+            _ => {}
+        }
+
+        let resp = resp.error_for_status()?;
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(<_>::to_owned);
+
+        let versions = resp
+            .text()
+            .await?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Response::Modified(File {
+            etag,
+            versions,
+            missing: 0,
+        }))
+    }
+}
+
+/// Index source which polls the sparse index for updates of subscribed
+/// crates every `pull_delay`.
+pub struct SparseSource {
+    registry: Registry,
+    index: SparseIndex,
+    db: Database,
+    pull_delay: Duration,
+    /// Crates which are yet to be polled in the current round
+    queue: VecDeque<String>,
+    /// Whether at least one round was started
+    started: bool,
+}
+
+impl SparseSource {
+    pub fn new(registry: &Registry, pull_delay: Duration, db: Database) -> Self {
+        Self {
+            registry: registry.clone(),
+            index: SparseIndex::new(registry.sparse_index_url.clone(), registry.http.clone()),
+            db,
+            pull_delay,
+            queue: VecDeque::new(),
+            started: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl IndexSource for SparseSource {
+    async fn next(&mut self) -> Option<Result<Batch, super::Error>> {
+        loop {
+            let krate = match self.queue.pop_front() {
+                Some(krate) => krate,
+                None => {
+                    let name = &self.registry.name;
+                    if self.started {
+                        log::info!("polling updates of {name} finished");
+
+                        // delay for `config.pull_delay` (default 5 min)
+                        tokio::time::sleep(self.pull_delay).await;
+                    }
+
+                    // States of files are restored once, afterwards they are only updated
+                    if !self.started {
+                        match self.db.sparse_files(name).await {
+                            Ok(files) => {
+                                for (krate, state) in files {
+                                    if let Err(err) = self.index.restore(&krate, &state) {
+                                        log::warn!("couldn't restore state of {krate}: {err}");
+                                    }
+                                }
+                            }
+                            Err(err) => return Some(Err(err.into())),
+                        }
+                    }
+
+                    log::info!("start polling updates of {name}");
+                    self.started = true;
+                    match self.db.list_crates().await {
+                        Ok(crates) => {
+                            let registry = &self.registry;
+                            let crates =
+                                crates.filter_map(|c| registry.unqualify(&c).map(<_>::to_owned));
+                            self.queue.extend(crates)
+                        }
+                        Err(err) => return Some(Err(err.into())),
+                    }
+
+                    continue;
+                }
+            };
+
+            let before = self.index.state(&krate);
+            match self.index.poll(&krate).await {
+                Ok(updates) => {
+                    let state = self.index.state(&krate);
+
+                    // Nothing to record
+                    if updates.is_empty() && state == before {
+                        continue;
+                    }
+
+                    let cursor = Some(Cursor::File { krate, state });
+                    return Some(Ok(Batch { updates, cursor }));
+                }
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+enum Response {
+    NotFound,
+    NotModified,
+    Modified(File),
+}
+
+/// Get updates from two versions of the same crate file.
+fn diff(name: &str, prev: &[Crate], next: &[Crate]) -> Vec<Update> {
+    let same = |a: &Crate, b: &Crate| a.id == b.id && a.yanked == b.yanked;
+
+    let diff = FileDiff {
+        deleted: false,
+        removed: prev
+            .iter()
+            .filter(|p| !next.iter().any(|n| same(p, n)))
+            .cloned()
+            .collect(),
+        added: next
+            .iter()
+            .filter(|n| !prev.iter().any(|p| same(p, n)))
+            .cloned()
+            .collect(),
+        file: next.to_vec(),
+    };
+
+    diff.into_updates(&format!("sparse index file of {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{hash_map::DefaultHasher, HashMap},
+        hash::{Hash, Hasher},
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{Client, SparseIndex, MISSING_POLLS_TO_DELETE};
+    use crate::index::ActionKind;
+
+    /// Local stand-in for the sparse index.
+    #[derive(Clone, Default)]
+    struct Stub {
+        /// Served files (path -> contents)
+        files: Arc<Mutex<HashMap<String, String>>>,
+        /// `If-None-Match` headers of all requests so far
+        etags: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl Stub {
+        /// Starts serving files, every file has an etag derived from its
+        /// contents. Returns the url of the index.
+        fn serve(&self) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let this = self.clone();
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(&stream);
+                    let mut request = String::new();
+                    reader.read_line(&mut request).unwrap();
+
+                    let mut if_none_match = None;
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).unwrap();
+                        match header.trim_end().split_once(": ") {
+                            Some((name, value)) if name.eq_ignore_ascii_case("if-none-match") => {
+                                if_none_match = Some(value.to_owned())
+                            }
+                            Some(_) => {}
+                            None => break,
+                        }
+                    }
+                    this.etags.lock().unwrap().push(if_none_match.clone());
+
+                    let path = request.split(' ').nth(1).unwrap().trim_start_matches('/');
+                    let resp = match this.files.lock().unwrap().get(path) {
+                        Some(body) if if_none_match == Some(etag(body)) => {
+                            String::from("HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n")
+                        }
+                        Some(body) => format!(
+                            "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\nConnection: \
+                             close\r\n\r\n{body}",
+                            etag(body),
+                            body.len()
+                        ),
+                        None => String::from(
+                            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: \
+                             close\r\n\r\n",
+                        ),
+                    };
+
+                    stream.write_all(resp.as_bytes()).unwrap();
+                }
+            });
+
+            format!("http://{addr}")
+        }
+
+        fn set(&self, lines: &[String]) {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(String::from("se/rd/serde"), lines.join("\n"));
+        }
+
+        fn remove(&self) {
+            self.files.lock().unwrap().clear();
+        }
+
+        fn last_etag(&self) -> Option<String> {
+            self.etags.lock().unwrap().last().cloned().flatten()
+        }
+    }
+
+    fn etag(body: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        format!("\"{:x}\"", hasher.finish())
+    }
+
+    fn line(vers: &str, yanked: bool) -> String {
+        let meta = format!(r#""cksum":"{vers}-sum","features":{{"std":[]}}"#);
+        format!(r#"{{"name":"serde","vers":"{vers}",{meta},"yanked":{yanked}}}"#)
+    }
+
+    #[tokio::test]
+    async fn poll() {
+        let stub = Stub::default();
+        let url = stub.serve();
+        let mut index = SparseIndex::new(url.clone(), Client::new());
+
+        stub.set(&[line("1.0.0", false)]);
+        assert!(index.poll("serde").await.unwrap().is_empty());
+
+        stub.set(&[line("1.0.0", true), line("1.0.1", false)]);
+        let updates = index.poll("serde").await.unwrap();
+        let prev = updates[1].prev.as_ref().map(|krate| krate.id.vers.as_str());
+        assert_eq!(prev, Some("1.0.0"));
+        let updates: Vec<_> = updates
+            .iter()
+            .map(|update| (update.krate.id.vers.as_str(), &update.action))
+            .collect();
+        assert_eq!(
+            updates,
+            [
+                ("1.0.0", &ActionKind::Yanked),
+                ("1.0.1", &ActionKind::NewVersion)
+            ]
+        );
+
+        // The state survives restarts, with full index entries
+        let mut index = {
+            let mut restored = SparseIndex::new(url, Client::new());
+            restored
+                .restore("serde", &index.state("serde").unwrap())
+                .unwrap();
+            restored
+        };
+
+        stub.set(&[line("1.0.1", false), line("1.0.2", false)]);
+        let updates = index.poll("serde").await.unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].krate.id.vers, "1.0.2");
+        assert_eq!(updates[0].action, ActionKind::NewVersion);
+        let prev = updates[0].prev.as_ref().unwrap();
+        assert_eq!(prev.cksum, "1.0.1-sum");
+        assert!(prev.features.contains_key("std"));
+        assert_eq!(updates[1].krate.id.vers, "1.0.0");
+        assert_eq!(updates[1].action, ActionKind::VersionRemoved);
+
+        assert!(index.fetch("nope").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn not_modified() {
+        let stub = Stub::default();
+        let mut index = SparseIndex::new(stub.serve(), Client::new());
+
+        stub.set(&[line("1.0.0", false)]);
+        assert!(index.poll("serde").await.unwrap().is_empty());
+        assert_eq!(stub.last_etag(), None);
+
+        // The file didn't change, so the index answers `304 Not Modified`
+        let before = index.state("serde");
+        assert!(index.poll("serde").await.unwrap().is_empty());
+        assert_eq!(stub.last_etag(), Some(etag(&line("1.0.0", false))));
+        assert_eq!(index.state("serde"), before);
+
+        // Once it changes, the new etag is used
+        stub.set(&[line("1.0.0", false), line("1.0.1", false)]);
+        assert_eq!(index.poll("serde").await.unwrap().len(), 1);
+        assert!(index.poll("serde").await.unwrap().is_empty());
+        let body = [line("1.0.0", false), line("1.0.1", false)].join("\n");
+        assert_eq!(stub.last_etag(), Some(etag(&body)));
+    }
+
+    #[tokio::test]
+    async fn deleted_after_missing_polls() {
+        let stub = Stub::default();
+        let mut index = SparseIndex::new(stub.serve(), Client::new());
+
+        stub.set(&[line("1.0.0", false), line("1.0.1", false)]);
+        assert!(index.poll("serde").await.unwrap().is_empty());
+
+        // A file which is missing only for a short while is fine
+        stub.remove();
+        assert!(index.poll("serde").await.unwrap().is_empty());
+        stub.set(&[line("1.0.0", false), line("1.0.1", false)]);
+        assert!(index.poll("serde").await.unwrap().is_empty());
+
+        // The countdown starts over after the file is back
+        stub.remove();
+        for _ in 1..MISSING_POLLS_TO_DELETE {
+            assert!(index.poll("serde").await.unwrap().is_empty());
+        }
+        let updates = index.poll("serde").await.unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].krate.id.vers, "1.0.1");
+        assert_eq!(updates[0].action, ActionKind::Deleted);
+
+        // Nothing is left to delete
+        assert!(index.poll("serde").await.unwrap().is_empty());
+        assert!(index.state("serde").is_none());
+        assert!(index.fetch("serde").await.unwrap().is_none());
+    }
+}
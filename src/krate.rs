@@ -1,18 +1,51 @@
-use crate::{cfg::Config, util::crate_path};
-use std::path::Path;
+use crate::{
+    cfg::{IndexBackend, Registry},
+    index::sparse::SparseIndex,
+    util::crate_path,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+use teloxide::utils::html;
 use tokio::{
     fs::File,
     io,
     io::{AsyncBufReadExt, BufReader},
 };
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// A single entry (line) of the registry index, see
+/// <https://doc.rust-lang.org/cargo/reference/registry-index.html#json-schema>.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Crate {
-    // TODO: stole from crates.io repo?
     #[serde(flatten)]
     pub id: CrateId,
+    /// Direct dependencies of the version.
+    #[serde(default)]
+    pub deps: Vec<Dependency>,
+    /// SHA256 checksum of the `.crate` file.
+    #[serde(default)]
+    pub cksum: String,
+    /// Features of the crate, mapping feature names to what they enable.
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Features using the newer syntax (`dep:` and `?/`), stored separately so
+    /// that older cargo versions don't choke on them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features2: Option<BTreeMap<String, Vec<String>>>,
     pub yanked: bool,
-    // ignore all unrelated stuff :D
+    /// The `links` value from the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub links: Option<String>,
+    /// Minimal supported rust version, `rust-version` from the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
+    /// Time of publishing (RFC 3339), only present in newer index entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubtime: Option<String>,
+    /// Index schema version, `2` if `features2` is used.
+    #[serde(default = "schema_v1")]
+    pub v: u32,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
@@ -21,49 +54,322 @@ pub struct CrateId {
     pub vers: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Dependency {
+    /// Name of the dependency, if it was renamed this is the new name (and the
+    /// original one is in `package`).
+    pub name: String,
+    /// Semver requirement of the dependency.
+    pub req: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(default = "yes")]
+    pub default_features: bool,
+    /// Platform (`cfg(...)` or a target triple) the dependency is specific to.
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub kind: DependencyKind,
+    /// URL of the registry of the dependency, `None` means the same registry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// Original name of the dependency, if it was renamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+    #[default]
+    Normal,
+    Build,
+    Dev,
+}
+
+fn schema_v1() -> u32 {
+    1
+}
+
+fn yes() -> bool {
+    true
+}
+
 impl Crate {
     // TODO: struct: Display
 
-    pub fn cratesio(&self) -> String {
-        format!("https://crates.io/crates/{krate}", krate = self.id.name)
-    }
+    pub fn html_links(&self, registry: &Registry) -> String {
+        registry
+            .links
+            .iter()
+            .map(|link| {
+                let url = link
+                    .url
+                    .replace("{name}", &self.id.name)
+                    .replace("{version}", &self.id.vers);
 
-    pub fn librs(&self) -> String {
-        format!("https://lib.rs/crates/{krate}", krate = self.id.name)
+                format!("<a href='{url}'>[{}]</a>", link.name)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
     }
 
-    pub fn docsrs(&self) -> String {
-        // Note:
-        // The full url is actually "https://docs.rs/{krate}/{version}/{krate}"
-        // but for some crates it doesn't hold e.g.: https://docs.rs/lsk/0.2.0/ls_key/
-        // Names differ                                              ^^^       ^^^^^^
-        //
-        // Anyway, "https://docs.rs/{krate}/{version}" redirects to the right place
-        format!(
-            "https://docs.rs/{krate}/{version}",
-            krate = self.id.name,
-            version = self.id.vers,
-        )
+    /// Describe what changed compared to the `prev` version: dependencies,
+    /// features and MSRV. Returns html lines (empty if nothing changed).
+    pub fn changes_since(&self, prev: &Crate) -> Vec<String> {
+        fn deps(krate: &Crate) -> BTreeMap<(DependencyKind, &str, Option<&str>), &str> {
+            krate
+                .deps
+                .iter()
+                .map(|dep| ((dep.kind, &*dep.name, dep.target.as_deref()), &*dep.req))
+                .collect()
+        }
+
+        fn features(krate: &Crate) -> BTreeSet<&str> {
+            let features2 = krate.features2.iter().flatten();
+            krate
+                .features
+                .iter()
+                .chain(features2)
+                .map(|(name, _)| &**name)
+                .collect()
+        }
+
+        let dep = |(kind, name, target): (DependencyKind, &str, Option<&str>)| {
+            let kind = match kind {
+                DependencyKind::Normal => "",
+                DependencyKind::Build => "build-",
+                DependencyKind::Dev => "dev-",
+            };
+            let target = target
+                .map(|t| format!(" (<code>{}</code>)", html::escape(t)))
+                .unwrap_or_default();
+
+            format!("{kind}dependency <code>{name}</code>{target}")
+        };
+
+        let mut lines = Vec::new();
+
+        let (prev_deps, next_deps) = (deps(prev), deps(self));
+        for (&key, &req) in &next_deps {
+            match prev_deps.get(&key) {
+                None => lines.push(format!("+ {} <code>{}</code>", dep(key), html::escape(req))),
+                Some(&old) if old != req => lines.push(format!(
+                    "~ {}: <code>{}</code> → <code>{}</code>",
+                    dep(key),
+                    html::escape(old),
+                    html::escape(req)
+                )),
+                Some(_) => {}
+            }
+        }
+        for &key in prev_deps.keys().filter(|key| !next_deps.contains_key(key)) {
+            lines.push(format!("- {}", dep(key)));
+        }
+
+        let (prev_features, next_features) = (features(prev), features(self));
+        for name in next_features.difference(&prev_features) {
+            lines.push(format!("+ feature <code>{name}</code>"));
+        }
+        for name in prev_features.difference(&next_features) {
+            lines.push(format!("- feature <code>{name}</code>"));
+        }
+
+        if self.rust_version != prev.rust_version {
+            let msrv = |v: &Option<String>| html::escape(v.as_deref().unwrap_or("none"));
+            lines.push(format!(
+                "MSRV: <code>{}</code> → <code>{}</code>",
+                msrv(&prev.rust_version),
+                msrv(&self.rust_version)
+            ));
+        }
+
+        lines
     }
 
-    pub fn html_links(&self) -> String {
-        format!(
-            "<a href='{docs}'>[docs.rs]</a> <a href='{crates}'>[crates.io]</a> <a \
-             href='{lib}'>[lib.rs]</a>",
-            docs = self.docsrs(),
-            crates = self.cratesio(),
-            lib = self.librs(),
-        )
+    pub async fn read_last(name: &str, registry: &Registry) -> io::Result<Self> {
+        Self::read_all(name, registry)
+            .await?
+            .pop()
+            .ok_or_else(|| std::io::ErrorKind::NotFound.into())
     }
 
-    pub async fn read_last(name: &str, cfg: &Config) -> io::Result<Self> {
-        let file = File::open(Path::new(cfg.index_path.as_str()).join(crate_path(name))).await?;
+    /// Reads all versions of the crate from the index, in the index order
+    /// (i.e. the order of publishing).
+    pub async fn read_all(name: &str, registry: &Registry) -> io::Result<Vec<Self>> {
+        if let IndexBackend::Sparse = registry.index_backend {
+            return SparseIndex::new(registry.sparse_index_url.clone(), registry.http.clone())
+                .fetch(name)
+                .await
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into());
+        }
+
+        let path = Path::new(registry.index_path.as_str()).join(crate_path(name));
+        let file = File::open(path).await?;
         let mut lines = BufReader::new(file).lines();
-        let mut last = None;
-        while let next @ Some(_) = lines.next_line().await? {
-            last = next
+        let mut versions = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            if line.is_empty() {
+                continue;
+            }
+
+            let krate = serde_json::from_str(&line)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            versions.push(krate);
+        }
+
+        Ok(versions)
+    }
+
+    /// Reads the last version of the crate, like [`Crate::read_last`], but
+    /// `name` may differ from the real name in case or `-`/`_` (the real name
+    /// is in the returned crate).
+    pub async fn find(name: &str, registry: &Registry) -> io::Result<Self> {
+        let mut res = Self::read_last(name, registry).await;
+
+        // Crate files are named in lowercase, so only separators need to be tried
+        for other in [name.replace('-', "_"), name.replace('_', "-")] {
+            if res.is_ok() {
+                break;
+            }
+
+            if other != name {
+                res = Self::read_last(&other, registry).await;
+            }
         }
-        serde_json::from_str(&last.unwrap())
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crate, DependencyKind};
+    use std::convert::TryInto;
+
+    fn parse(fixture: &str) -> Vec<Crate> {
+        fixture
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse_v2() {
+        let [krate]: [Crate; 1] = parse(include_str!("../tests/fixtures/libgit2-sys"))
+            .try_into()
+            .unwrap();
+
+        assert_eq!(krate.id.name, "libgit2-sys");
+        assert_eq!(krate.id.vers, "0.14.2+1.5.1");
+        assert_eq!(krate.v, 2);
+        assert_eq!(krate.links.as_deref(), Some("git2"));
+        assert_eq!(krate.pubtime.as_deref(), Some("2023-01-20T22:55:08Z"));
+        assert_eq!(
+            krate.cksum,
+            "7f3d95f6b51075fe9810a7ae22c7095f12b98005ab364d8544797a825ce946a4"
+        );
+        assert!(!krate.yanked);
+
+        let kinds = krate.deps.iter().map(|d| (d.name.as_str(), d.kind));
+        assert!(kinds.eq([
+            ("cc", DependencyKind::Build),
+            ("libc", DependencyKind::Normal),
+            ("libssh2-sys", DependencyKind::Normal),
+            ("libz-sys", DependencyKind::Normal),
+            ("openssl-sys", DependencyKind::Normal),
+            ("pkg-config", DependencyKind::Build),
+        ]));
+
+        let openssl = &krate.deps[4];
+        assert_eq!(openssl.req, "^0.9");
+        assert!(openssl.optional);
+        assert_eq!(openssl.target.as_deref(), Some("cfg(unix)"));
+
+        let libz = &krate.deps[3];
+        assert!(!libz.default_features);
+        assert_eq!(libz.features, ["libc"]);
+
+        assert_eq!(krate.features.len(), 5);
+        assert_eq!(krate.features["https"], ["openssl-sys"]);
+        assert_eq!(
+            krate.features2.as_ref().unwrap()["zlib-ng-compat"],
+            ["libz-sys/zlib-ng", "libssh2-sys?/zlib-ng-compat"]
+        );
+    }
+
+    #[test]
+    fn parse_versions() {
+        let versions = parse(include_str!("../tests/fixtures/volatile"));
+        let ids = versions.iter().map(|k| (k.id.vers.as_str(), k.v, k.yanked));
+        assert!(ids.eq([
+            ("0.5.0", 1, false),
+            ("0.5.3-rc.1", 2, false),
+            ("0.6.0", 2, true)
+        ]));
+
+        assert!(versions[0].features2.is_none());
+        assert_eq!(versions[0].deps[0].kind, DependencyKind::Dev);
+        assert_eq!(
+            versions[1].features2.as_ref().unwrap()["derive"],
+            ["dep:volatile-macro"]
+        );
+    }
+
+    #[test]
+    fn changes() {
+        let versions = parse(include_str!("../tests/fixtures/volatile"));
+
+        assert_eq!(
+            versions[2].changes_since(&versions[1]),
+            [
+                "~ dependency <code>volatile-macro</code>: <code>=0.5.3-rc.1</code> → \
+                 <code>=0.5.4</code>"
+            ]
+        );
+        assert_eq!(
+            versions[1].changes_since(&versions[0]),
+            [
+                "+ dependency <code>volatile-macro</code> <code>=0.5.3-rc.1</code>",
+                "+ feature <code>derive</code>",
+            ]
+        );
+        assert_eq!(
+            versions[0].changes_since(&versions[1]),
+            [
+                "- dependency <code>volatile-macro</code>",
+                "- feature <code>derive</code>",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_renamed() {
+        let [krate]: [Crate; 1] = parse(include_str!("../tests/fixtures/xml"))
+            .try_into()
+            .unwrap();
+
+        assert_eq!(krate.rust_version.as_deref(), Some("1.63"));
+        assert!(krate.links.is_none());
+        assert_eq!(krate.v, 1);
+
+        let dep = &krate.deps[0];
+        assert_eq!(dep.name, "xml_rs");
+        assert_eq!(dep.package.as_deref(), Some("xml-rs"));
+        assert_eq!(dep.req, "=0.8.9");
     }
 }
@@ -0,0 +1,168 @@
+//! Parsing of `Cargo.lock` files, used to subscribe to all dependencies at
+//! once.
+use std::collections::BTreeMap;
+
+use semver::{BuildMetadata, Version, VersionReq};
+
+use crate::cfg::Config;
+
+#[derive(serde::Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<Package>,
+}
+
+#[derive(serde::Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    /// `None` for path dependencies (e.g. workspace members)
+    source: Option<String>,
+}
+
+/// Packages of a lockfile, resolved to the known registries.
+pub struct Locked {
+    /// Qualified crate names and requirements matching the locked versions and
+    /// their compatible updates
+    pub crates: Vec<(String, VersionReq)>,
+    /// Qualified crate names and exact locked versions
+    pub versions: Vec<(String, String)>,
+    /// Number of packages which are not from a known registry (e.g. git or
+    /// path dependencies)
+    pub skipped: usize,
+}
+
+/// Parses `Cargo.lock` and resolves its packages to the known registries.
+///
+/// Every crate gets a requirement matching the locked version and newer
+/// compatible ones (`^locked`). If several versions of the same crate are
+/// locked, the requirement matches everything since the oldest one
+/// (`>=oldest`).
+pub fn parse(lockfile: &str, cfg: &Config) -> Result<Locked, toml::de::Error> {
+    let Lockfile { package } = toml::from_str(lockfile)?;
+
+    let mut skipped = 0;
+    let mut locked = Vec::new();
+    let mut versions = BTreeMap::<String, Vec<Version>>::new();
+    for Package {
+        name,
+        version,
+        source,
+    } in package
+    {
+        let registry = source
+            .as_deref()
+            .and_then(|source| cfg.registries.values().find(|r| r.is_source(source)));
+
+        match (registry, version.parse::<Version>()) {
+            (Some(registry), Ok(mut parsed)) => {
+                let krate = registry.qualify(&name);
+
+                // Build metadata is not allowed in requirements (and doesn't
+                // affect precedence anyway)
+                parsed.build = BuildMetadata::EMPTY;
+                versions.entry(krate.clone()).or_default().push(parsed);
+                locked.push((krate, version));
+            }
+            _ => skipped += 1,
+        }
+    }
+
+    let crates = versions
+        .into_iter()
+        .filter_map(|(krate, mut versions)| {
+            versions.sort();
+            let req = match &versions[..] {
+                [locked] => format!("^{locked}"),
+                [oldest, ..] => format!(">={oldest}"),
+                [] => return None,
+            };
+
+            // Versions are valid, so requirements made of them are valid too
+            req.parse().ok().map(|req| (krate, req))
+        })
+        .collect();
+
+    Ok(Locked {
+        crates,
+        versions: locked,
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::cfg::Config;
+
+    const CONFIG: &str = r#"
+        bot_token = ""
+        db = { host = "", user = "", dbname = "" }
+
+        [registries.corp]
+        index_backend = "sparse"
+        sparse_index_url = "https://index.corp.example/"
+    "#;
+
+    const LOCKFILE: &str = r#"
+        version = 3
+
+        [[package]]
+        name = "app"
+        version = "0.1.0"
+
+        [[package]]
+        name = "corp-utils"
+        version = "1.2.0"
+        source = "sparse+https://index.corp.example"
+
+        [[package]]
+        name = "fork"
+        version = "0.3.0"
+        source = "git+https://github.com/someone/fork?branch=main#0123456789abcdef"
+
+        [[package]]
+        name = "libgit2-sys"
+        version = "0.14.2+1.5.1"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+
+        [[package]]
+        name = "syn"
+        version = "1.0.109"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+
+        [[package]]
+        name = "syn"
+        version = "2.0.15"
+        source = "sparse+https://index.crates.io/"
+    "#;
+
+    #[test]
+    fn resolves_packages() {
+        let cfg = Config::parse(CONFIG).unwrap();
+        let locked = parse(LOCKFILE, &cfg).unwrap();
+
+        let crates: Vec<_> = locked
+            .crates
+            .iter()
+            .map(|(krate, req)| (krate.as_str(), req.to_string()))
+            .collect();
+        assert_eq!(
+            crates,
+            [
+                ("corp:corp-utils", String::from("^1.2.0")),
+                ("libgit2-sys", String::from("^0.14.2")),
+                ("syn", String::from(">=1.0.109")),
+            ]
+        );
+
+        // Locked versions are kept as is
+        assert!(locked
+            .versions
+            .contains(&("libgit2-sys".into(), "0.14.2+1.5.1".into())));
+        assert_eq!(locked.versions.len(), 4);
+
+        // `app` (path) and `fork` (git)
+        assert_eq!(locked.skipped, 2);
+    }
+}
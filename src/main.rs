@@ -1,35 +1,47 @@
-// TODO: somehow better handle rate-limits (https://core.telegram.org/bots/faq#broadcasting-to-users)
-//       maybe concat many messages into one (in channel) + queues to properly
-//       handle limits
-
-// When index collapses, use `git reset --hard origin/master`
 #![allow(clippy::type_complexity)]
-use std::{convert::Infallible, iter, sync::Arc, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use arraylib::Slice;
-use either::Either::{Left, Right};
-use fntools::{self, value::ValueExt};
-use futures::future::{self, pending};
-use git2::{Commit, Delta, Diff, DiffOptions, Repository, Sort};
+use futures::{
+    future::{self, pending},
+    Future, FutureExt,
+};
 use log::info;
-use std::str;
 use teloxide::{adaptors::DefaultParseMode, prelude::*, types::ParseMode};
-use tokio::sync::{
-    mpsc::{self, Sender},
-    oneshot,
-};
 use tokio_postgres::NoTls;
 
-use crate::{db::Database, krate::Crate, util::tryn};
+use crate::{
+    cfg::{Aggregation, IndexBackend, Registry},
+    db::{ChannelPost, Database, Event, Fanout, Route},
+    index::{git::GitIndex, sparse::SparseSource, ActionKind, Batch, IndexSource, Update},
+};
 
 mod bot;
 mod cfg;
 mod db;
+mod digest;
+mod index;
 mod krate;
+mod lockfile;
+mod names;
+mod outbox;
 mod util;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Maximum number of changes (dependencies, features, etc) listed in a new
+/// version notification.
+const MAX_CHANGES: usize = 20;
+
+/// Maximum length of an aggregated channel post (telegram limit is 4096
+/// characters), a new post is started when it's reached.
+const MAX_POST_LEN: usize = 4000;
+
+/// Maximum number of recorded updates delivered at once.
+const EVENTS_BATCH_SIZE: i32 = 100;
+
+/// Delay before checking for recorded updates again when there were none.
+const EVENTS_POLL_DELAY: Duration = Duration::from_secs(1);
+
 type Bot = DefaultParseMode<teloxide::Bot>;
 
 #[tokio::main]
@@ -67,352 +79,370 @@ async fn main() {
         d
     };
 
-    let index_url = &config.index_url; // Closures still borrow full struct :|
-    let index_path = &config.index_path;
-    let repo = Repository::open(index_path).unwrap_or_else(move |_| {
-        info!("start cloning");
-        Repository::clone(index_url, index_path)
-            .unwrap()
-            .also(|_| info!("cloning finished"))
-    });
-
-    let (abortable, abort_handle) = future::abortable(pending::<()>());
-
-    let (tx, mut rx) = mpsc::channel(2);
-    let git2_th = {
-        let pull_delay = config.pull_delay;
-        std::thread::spawn(move || {
-            'outer: loop {
-                log::info!("start pulling updates");
-
-                if let Err(err) = pull(&repo, tx.clone()) {
-                    log::error!("couldn't pull new crate version from the index: {}", err)
-                }
-
-                log::info!("pulling updates finished");
-
-                // delay for `config.pull_delay` (default 5 min)
-                {
-                    let mut pd = pull_delay;
-                    const STEP: Duration = Duration::from_secs(5);
+    let mut sources = Vec::new();
+    for registry in config.registries.values() {
+        let source: Box<dyn IndexSource> = match registry.index_backend {
+            IndexBackend::Git => Box::new(
+                GitIndex::open(registry, config.pull_delay, db.clone())
+                    .await
+                    .unwrap_or_else(|e| panic!("couldn't open {} index: {e}", registry.name)),
+            ),
+            IndexBackend::Sparse => {
+                Box::new(SparseSource::new(registry, config.pull_delay, db.clone()))
+            }
+        };
 
-                    while pd > Duration::ZERO {
-                        if abortable.is_aborted() {
-                            break 'outer;
-                        }
+        sources.push((registry, source));
+    }
 
-                        pd = pd.saturating_sub(STEP);
-                        std::thread::sleep(STEP);
-                    }
-                }
-            }
-        })
-    };
+    let (stop, abort_handle) = future::abortable(pending::<()>());
+    let stop = stop.shared();
 
     let bot = teloxide::Bot::new(&config.bot_token).parse_mode(ParseMode::Html);
 
-    let notify_loop = async {
-        while let Some((res, _unblock)) = rx.recv().await {
-            match res {
-                Ok((krate, action)) => notify(krate, action, &bot, &db, &config).await,
-                Err(e) => {
-                    log::error!("diff_one error: {e:?}");
-                    if let Some(chat_id) = config.error_report_channel_id {
-                        bot.send_message(chat_id, format!("diff_one error: {e:?}"))
-                            .await
-                            .ok();
-                    }
-                }
-            }
-
-            // implicitly unblock git2 thread by dropping `_unblock`
-        }
+    let notify_loops = future::join_all(sources.iter_mut().map(|(registry, source)| {
+        notify_loop(registry, &mut **source, stop.clone(), &bot, &db, &config)
+    }));
 
-        // `recv()` returned `None` => `tx` was dropped => `git2_th` was stopped
-        // => `abort_handle.abort()` was probably called
-    };
+    let deliver_loop = deliver_loop(stop.clone(), &db, &config);
+    let digest_loop = digest::run(stop.clone(), &db);
+    let outbox_loop = outbox::run(stop.clone(), &bot, &db, &config);
 
     let tg_loop = async {
         bot::run(bot.clone(), db.clone(), Arc::clone(&config)).await;
 
-        // When bot stopped executing (e.g. because of ^C) stop pull loop
+        // When bot stopped executing (e.g. because of ^C) stop notify loops
         abort_handle.abort();
     };
 
-    tokio::join!(notify_loop, tg_loop);
+    tokio::join!(
+        notify_loops,
+        deliver_loop,
+        digest_loop,
+        outbox_loop,
+        tg_loop
+    );
 
-    git2_th.join().unwrap();
+    // Stops the sources (e.g. git2 threads)
+    drop(sources);
 }
 
-/// Fast-Forward (FF) to a given commit.
-///
-/// Implementation is taken from <https://stackoverflow.com/a/58778350>.
-fn fast_forward(repo: &Repository, commit: &git2::Commit) -> Result<(), git2::Error> {
-    let fetch_commit = repo.find_annotated_commit(commit.id())?;
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-
-    if analysis.0.is_up_to_date() {
-        Ok(())
-    } else if analysis.0.is_fast_forward() {
-        let mut reference = repo.find_reference("refs/heads/master")?;
-        reference.set_target(fetch_commit.id(), "Fast-Forward")?;
-        repo.set_head(reference.name().unwrap())?;
-        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
-    } else {
-        Err(git2::Error::from_str("Fast-forward only!"))
+/// Record updates from `source` until `stop` resolves, notifications are sent
+/// by [`deliver_loop`].
+async fn notify_loop(
+    registry: &Registry,
+    source: &mut dyn IndexSource,
+    mut stop: impl Future + Unpin,
+    bot: &Bot,
+    db: &Database,
+    cfg: &cfg::Config,
+) {
+    loop {
+        let res = tokio::select! {
+            biased;
+            res = source.next() => res,
+            _ = &mut stop => break,
+        };
+
+        match res {
+            Some(Ok(Batch { updates, cursor })) => {
+                // The source has already moved on, so retry until the updates are recorded
+                while let Err(err) = db
+                    .record_events(&registry.name, &updates, cursor.as_ref())
+                    .await
+                {
+                    log::error!(
+                        "db error while recording {} updates: {}",
+                        registry.name,
+                        err
+                    );
+
+                    // The cursor wasn't moved, so the updates will be pulled again after a
+                    // restart
+                    tokio::select! {
+                        _ = tokio::time::sleep(cfg.retry_delay.0) => {}
+                        _ = &mut stop => return,
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                log::error!("{} index error: {e:?}", registry.name);
+                if let Some(chat_id) = cfg.error_report_channel_id {
+                    bot.send_message(chat_id, format!("{} index error: {e:?}", registry.name))
+                        .await
+                        .ok();
+                }
+            }
+            None => break,
+        }
     }
 }
 
-fn pull(
-    repo: &Repository,
-    ch: Sender<(
-        Result<(Crate, ActionKind), git2::Error>,
-        oneshot::Sender<Infallible>,
-    )>,
-) -> Result<(), git2::Error> {
-    // fetch changes from remote index
-    repo.find_remote("origin")?.fetch(&["master"], None, None)?;
-
-    // Collect all commits in the range `HEAD~1..FETCH_HEAD` (i.e. one before
-    // currently checked out to the last fetched)
-    let mut walk = repo.revwalk()?;
-    walk.push_range("HEAD~1..FETCH_HEAD")?;
-    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
-    let commits: Result<Vec<_>, _> = walk.map(|oid| repo.find_commit(oid?)).collect();
-
-    let mut opts = DiffOptions::default();
-    let opts = opts.context_lines(0).minimal(true);
-
-    for [prev, next] in Slice::array_windows::<[_; 2]>(&commits?[..]) {
-        let message = next
-            .message()
-            .unwrap_or("<invalid utf-8>")
-            .trim_end_matches('\n');
-
-        // Commits from humans tend to be formatted differently, compared to
-        // machine-generated ones. This basically makes them unanalyzable.
-        if next.author().name() != Some("bors") {
-            log::warn!(
-                "Skip commit#{} from non-bors user @{}: {message}",
-                next.id(),
-                next.author().name().unwrap_or("<invalid utf-8>"),
-            );
-
-            continue;
-        }
-
-        if message == "Crate version removal request" {
-            log::warn!("Skip crate version removal request commit#{}", next.id());
-            continue;
-        }
-
-        if message == "Delete crates" {
-            log::warn!("Skip delete crates commit#{}", next.id());
-            continue;
-        }
-
-        if message.starts_with("Merge remote-tracking branch")
-            || message.starts_with("Merge branch")
+/// Turn recorded updates into notifications until `stop` resolves.
+async fn deliver_loop(mut stop: impl Future + Unpin, db: &Database, cfg: &cfg::Config) {
+    loop {
+        let events = db
+            .pending_events(EVENTS_BATCH_SIZE)
+            .await
+            .unwrap_or_else(|err| {
+                log::error!("db error while getting events: {}", err);
+                Vec::new()
+            });
+
+        let mut idle = events.is_empty();
+        for Event {
+            id,
+            registry,
+            update,
+        } in events
         {
-            log::warn!("Skip merge commit#{}", next.id());
-            continue;
+            let (update, registry) = match (update, cfg.registries.get(&registry)) {
+                (Ok(update), Some(registry)) => (update, registry),
+                (Err(err), _) => {
+                    log::error!("couldn't read event#{id}, skipping it: {err}");
+                    drop_event(db, id).await;
+                    continue;
+                }
+                (_, None) => {
+                    log::warn!("event#{id} is from unknown registry {registry}, skipping it");
+                    drop_event(db, id).await;
+                    continue;
+                }
+            };
+
+            let name = registry.qualify(&update.krate.id.name);
+            let res = match notify(&update, &name, registry, db, cfg).await {
+                Ok(fanout) => db.finish_event(id, &name, &update, &fanout).await,
+                Err(err) => Err(err),
+            };
+
+            // The event stays in the db, so it will be retried
+            if let Err(err) = res {
+                log::error!("db error while delivering event#{id}: {}", err);
+                idle = true;
+                break;
+            }
         }
 
-        let diff = repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&next.tree()?), Some(opts))?;
-        let res = diff_one(diff, (prev, next));
-
-        // Send crates.io update to notifier
-        let (tx, mut rx) = oneshot::channel();
-        ch.blocking_send((res, tx)).ok().unwrap();
-
-        // Wait until the crate is processed before moving on
-        while let Err(oneshot::error::TryRecvError::Empty) = rx.try_recv() {
-            // Yield/sleep to not spend all resources
-            std::thread::sleep(Duration::from_secs(1));
+        if idle {
+            tokio::select! {
+                _ = tokio::time::sleep(EVENTS_POLL_DELAY) => {}
+                _ = &mut stop => break,
+            }
         }
-
-        // 'Move' to the next commit
-        fast_forward(repo, next)?;
     }
-
-    Ok(())
 }
 
-enum ActionKind {
-    NewVersion,
-    Yanked,
-    Unyanked,
+/// Removes the event which can't be delivered.
+async fn drop_event(db: &Database, id: i64) {
+    db.remove_event(id)
+        .await
+        .unwrap_or_else(|err| log::error!("db error while removing event#{id}: {}", err));
 }
 
-/// Get a `crates.io` update from a diff of 2 consecutive commits from a
-/// `crates.io-index` repository.
-fn diff_one(diff: Diff, commits: (&Commit, &Commit)) -> Result<(Crate, ActionKind), git2::Error> {
-    let mut prev = None;
-    let mut next = None;
-
-    let mut error = Ok(());
-
-    diff.foreach(
-        &mut |_, _| true,
-        None,
-        None,
-        Some(&mut |delta, _hunk, line| {
-            match delta.status() {
-                // New version of a crate or (un)yanked old version
-                Delta::Modified | Delta::Added => {
-                    if !(delta.nfiles() == 2 || delta.nfiles() == 1) {
-                        error = Err(format!("Unexpected delta.nfiles: {delta:?}"));
-                        return false;
-                    }
-
-                    match line.origin() {
-                        '-' => {
-                            if prev.is_some() {
-                                error =
-                                    Err("Expected number of deletions <= 1 per commit".to_owned());
-                                return false;
-                            }
-
-                            let krate = match str::from_utf8(line.content()) {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    error = Err(format!("Non UTF-8 diff: {e:?}"));
-                                    return false;
-                                }
-                            };
-                            let krate = match serde_json::from_str::<Crate>(krate) {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    error = Err(format!("Couldn't deserialize crate: {e:?}"));
-                                    return false;
-                                }
-                            };
-
-                            prev = Some(krate);
-                        }
-                        '+' => {
-                            if next.is_some() {
-                                error =
-                                    Err("Expected number of additions = 1 per commit".to_owned());
-                                return false;
-                            }
-
-                            let krate = match str::from_utf8(line.content()) {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    error = Err(format!("Non UTF-8 diff: {e:?}"));
-                                    return false;
-                                }
-                            };
-                            let krate = match serde_json::from_str::<Crate>(krate) {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    error = Err(format!("Couldn't deserialize crate: {e:?}"));
-                                    return false;
-                                }
-                            };
-
-                            next = Some(krate);
-                        }
-                        _ => { /* don't care */ }
-                    }
-                }
-                delta => {
-                    log::warn!("Unexpected delta: {:?}", delta);
-                }
-            }
+/// Decides who should be notified about the update (`name` is the qualified
+/// name of the crate) and how.
+async fn notify(
+    update: &Update,
+    name: &str,
+    registry: &Registry,
+    db: &Database,
+    cfg: &cfg::Config,
+) -> Result<Fanout, tokio_postgres::Error> {
+    let Update {
+        krate,
+        action,
+        prev,
+    } = update;
+    let mut fanout = Fanout::default();
+
+    let verb = match action {
+        ActionKind::NewVersion => "updated",
+        ActionKind::Yanked => "yanked",
+        ActionKind::Unyanked => "unyanked",
+        ActionKind::VersionRemoved => "removed",
+        ActionKind::Deleted => "deleted",
+    };
 
-            true
-        }),
-    )?;
+    let mut message = match action {
+        // There is nothing to link to anymore
+        ActionKind::Deleted => format!("Crate was deleted: <code>{name}</code>"),
+        _ => format!(
+            "Crate was {verb}: <code>{name}#{version}</code> {links}",
+            version = krate.id.vers,
+            links = krate.html_links(registry),
+        ),
+    };
 
-    let err =
-        |e| git2::Error::from_str(&format!("{e} ({} -> {})", commits.0.id(), commits.1.id(),));
+    if let Some(prev) = prev {
+        let mut changes = krate.changes_since(prev);
+        if !changes.is_empty() {
+            // Keep the message well below telegram limits
+            if changes.len() > MAX_CHANGES {
+                let more = changes.len() - MAX_CHANGES;
+                changes.truncate(MAX_CHANGES);
+                changes.push(format!("…and {more} more"));
+            }
 
-    if let Err(e) = error {
-        return Err(err(&*e));
+            message.push_str(&format!("\n\nChanges since {}:\n", prev.id.vers));
+            message.push_str(&changes.join("\n"));
+        }
     }
 
-    let next = next.ok_or_else(|| err("Expected number of additions = 1 per commit"))?;
-    match (prev.as_ref().map(|c| c.yanked), next.yanked) {
-        /* was yanked?, is yanked? */
-        (None, false) => {
-            // There were no deleted line & crate is not yanked.
-            // New version.
-            Ok((next, ActionKind::NewVersion))
-        }
-        (Some(false), true) => {
-            // The crate was not yanked and now is yanked.
-            // Crate was yanked.
-            Ok((next, ActionKind::Yanked))
-        }
-        (Some(true), false) => {
-            // The crate was yanked and now is not yanked.
-            // Crate was unyanked.
-            Ok((next, ActionKind::Unyanked))
-        }
-        _unexpected => {
-            // Something unexpected happened
-            Err(err(&format!(
-                "Unexpected diff_one input: {prev:?} -> {next:?} ",
-            )))
+    let users = db.list_subscribers(name).await?;
+
+    if let Some(chat_id) = registry.channel {
+        if !cfg.ban.crates.contains(name) {
+            match &cfg.channel_aggregation {
+                Some(aggregation) => {
+                    let line = match action {
+                        ActionKind::Deleted => format!("deleted <code>{name}</code>"),
+                        _ => format!(
+                            "{verb} <code>{name}#{version}</code> {links}",
+                            version = krate.id.vers,
+                            links = krate.html_links(registry),
+                        ),
+                    };
+
+                    let post = channel_post(chat_id, name, line, &message, aggregation, db);
+                    fanout.post = Some(post.await?);
+                }
+                None => fanout
+                    .messages
+                    .push((message.clone(), None, vec![(chat_id, true)])),
+            }
         }
     }
-}
 
-async fn notify(krate: Crate, action: ActionKind, bot: &Bot, db: &Database, cfg: &cfg::Config) {
-    let message = format!(
-        "Crate was {action}: <code>{krate}#{version}</code> {links}",
-        krate = krate.id.name,
-        version = krate.id.vers,
-        links = krate.html_links(),
-        action = match action {
-            ActionKind::NewVersion => "updated",
-            ActionKind::Yanked => "yanked",
-            ActionKind::Unyanked => "unyanked",
+    // Chats which depend on exactly this version get a separate alert instead
+    // of the ordinary message
+    let mut alerted = HashSet::new();
+    if let ActionKind::Yanked | ActionKind::VersionRemoved = action {
+        let watchers: Vec<_> = db
+            .list_watchers(name, &krate.id.vers)
+            .await?
+            .map(|chat_id| (chat_id, false))
+            .collect();
+
+        let alert = format!(
+            "⚠️ <b>A version you depend on was {action}</b>: <code>{name}#{version}</code> \
+             {links}\n\
+             \n\
+             It's locked in your <code>Cargo.lock</code> (or watched with /watch), consider \
+             updating it.",
+            version = krate.id.vers,
+            links = krate.html_links(registry),
+            action = match action {
+                ActionKind::VersionRemoved => "removed from the index",
+                _ => "yanked",
+            },
+        );
+
+        alerted.extend(watchers.iter().map(|&(chat_id, _)| chat_id));
+        if !watchers.is_empty() {
+            fanout.messages.push((alert, None, watchers));
         }
-    );
+    }
 
-    let users = db
-        .list_subscribers(&krate.id.name)
-        .await
-        .map(Left)
-        .map_err(|err| log::error!("db error while getting subscribers: {}", err))
-        .unwrap_or_else(|()| Right(iter::empty()));
+    // There is nothing to unsubscribe from after a deletion
+    let keyboard = match action {
+        ActionKind::Deleted => None,
+        _ => Some(bot::notification_keyboard(name, &krate.id.vers, action)),
+    };
 
-    if let Some(chat_id) = cfg.channel {
-        if !cfg.ban.crates.contains(krate.id.name.as_str()) {
-            notify_inner(bot, chat_id, &message, cfg, &krate, true).await;
+    let mut chats = Vec::new();
+    for sub in users
+        .filter(|sub| sub.wants(&krate.id.vers, action))
+        .filter(|sub| !alerted.contains(&sub.chat_id))
+    {
+        match sub.route() {
+            Route::Digest => fanout.digest.push(sub.chat_id),
+            Route::Held => fanout.held.push(sub.chat_id),
+            Route::Send { silent } => chats.push((sub.chat_id, silent)),
         }
     }
 
-    for chat_id in users {
-        notify_inner(bot, chat_id, &message, cfg, &krate, false).await;
-        tokio::time::sleep(cfg.broadcast_delay_millis.into()).await;
+    if !chats.is_empty() {
+        fanout.messages.push((message, keyboard, chats));
     }
+
+    // Everyone is notified, subscriptions to a crate that doesn't exist are
+    // useless
+    fanout.delete_crate = *action == ActionKind::Deleted;
+
+    Ok(fanout)
 }
 
-async fn notify_inner(
-    bot: &Bot,
+/// Adds the update (`line` in an aggregated post, `message` on its own) to
+/// the open channel post of its group, or starts a new one.
+async fn channel_post(
     chat_id: ChatId,
-    msg: &str,
-    cfg: &cfg::Config,
-    krate: &Crate,
-    quiet: bool,
-) {
-    tryn(5, cfg.retry_delay.0, || {
-        bot.send_message(chat_id, msg)
-            .disable_web_page_preview(true)
-            .disable_notification(quiet)
+    name: &str,
+    line: String,
+    message: &str,
+    aggregation: &Aggregation,
+    db: &Database,
+) -> Result<ChannelPost, tokio_postgres::Error> {
+    let group_key = aggregation.group_by.key(name);
+
+    if let Some((id, lines)) = db
+        .open_post(chat_id, &group_key, aggregation.window)
+        .await?
+    {
+        if let Some((lines, text)) = extend_post(lines, line.clone()) {
+            return Ok(ChannelPost {
+                chat_id,
+                id: Some(id),
+                group_key,
+                lines,
+                text,
+            });
+        }
+    }
+
+    // Until something else is added, the post is the ordinary message
+    Ok(ChannelPost {
+        chat_id,
+        id: None,
+        group_key,
+        lines: vec![line],
+        text: message.to_owned(),
     })
-    .await
-    .map(drop)
-    .unwrap_or_else(|err| {
-        log::error!(
-            "error while trying to send notification about {:?} to {}: {}",
-            krate,
-            chat_id,
-            err
-        )
-    });
+}
+
+/// Adds `line` to the lines of an open post and renders it, returns `None` if
+/// the post would be too long (a new one is started then).
+fn extend_post(mut lines: Vec<String>, line: String) -> Option<(Vec<String>, String)> {
+    lines.push(line);
+    let text = format!("📦 <b>{} updates</b>\n\n{}", lines.len(), lines.join("\n"));
+
+    (text.len() <= MAX_POST_LEN).then_some((lines, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extend_post, MAX_POST_LEN};
+
+    #[test]
+    fn post_overflow() {
+        let line = |n: usize| format!("<code>tokio-{n}</code> {}", "x".repeat(100));
+
+        let (lines, text) = extend_post(vec![line(0)], line(1)).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(text.starts_with("📦 <b>2 updates</b>\n\n"));
+        assert!(text.ends_with(&line(1)));
+
+        // Lines are added while the post fits
+        let mut lines = vec![line(0)];
+        let mut n = 1;
+        while let Some((extended, text)) = extend_post(lines.clone(), line(n)) {
+            assert!(text.len() <= MAX_POST_LEN);
+            lines = extended;
+            n += 1;
+        }
+
+        // The line that doesn't fit starts a new post
+        assert!(lines.len() > 30);
+        assert_eq!(lines.last(), Some(&line(n - 1)));
+    }
 }
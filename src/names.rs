@@ -0,0 +1,267 @@
+//! Crate names known to the local index, used to suggest existing crates when
+//! a user misspells a name.
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cfg::{Config, IndexBackend, Registry},
+    util::normalize_name,
+};
+
+/// How long the list of names is reused before reading the index again.
+const REFRESH: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of suggestions for a misspelled name.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Cache of crate names of every registry.
+#[derive(Clone, Default)]
+pub struct Names {
+    cached: Arc<Mutex<HashMap<String, (Instant, Arc<Vec<String>>)>>>,
+}
+
+impl Names {
+    /// Returns names of existing crates which are similar to `qualified` (e.g.
+    /// `serde_json` for `serde-json`), the nearest first.
+    ///
+    /// Only registries with a git index are supported, for others nothing is
+    /// suggested.
+    pub async fn suggest(&self, qualified: &str, cfg: &Config) -> Vec<String> {
+        self.find(qualified, cfg, |name, names| {
+            nearest(name, names, MAX_SUGGESTIONS)
+        })
+        .await
+    }
+
+    /// Returns up to `limit` names of existing crates which start with
+    /// `qualified` (ignoring case and `-`/`_`), the shortest first, followed
+    /// by similar names.
+    ///
+    /// Only registries with a git index are supported, for others nothing is
+    /// found.
+    pub async fn search(&self, qualified: &str, cfg: &Config, limit: usize) -> Vec<String> {
+        self.find(qualified, cfg, move |name, names| {
+            let mut found = starting_with(name, names, limit);
+            if found.len() < limit {
+                let similar: Vec<_> = nearest(name, names, limit)
+                    .into_iter()
+                    .filter(|name| !found.contains(name))
+                    .take(limit - found.len())
+                    .collect();
+                found.extend(similar);
+            }
+
+            found
+        })
+        .await
+    }
+
+    /// Finds crates of the registry of `qualified` with `f` (which gets the
+    /// unqualified name and all names of the registry).
+    async fn find<F>(&self, qualified: &str, cfg: &Config, f: F) -> Vec<String>
+    where
+        F: FnOnce(&str, &[String]) -> Vec<String> + Send + 'static,
+    {
+        let (registry, name) = match cfg.resolve(qualified) {
+            Some((registry, name)) if registry.index_backend == IndexBackend::Git => {
+                (registry, name)
+            }
+            _ => return Vec::new(),
+        };
+
+        let names = match self.names(registry).await {
+            Ok(names) => names,
+            Err(err) => {
+                log::error!("couldn't read crate names of {}: {err}", registry.name);
+                return Vec::new();
+            }
+        };
+
+        let name = name.to_owned();
+        let found = tokio::task::spawn_blocking(move || f(&name, &names))
+            .await
+            .unwrap_or_default();
+
+        found.iter().map(|name| registry.qualify(name)).collect()
+    }
+
+    async fn names(&self, registry: &Registry) -> io::Result<Arc<Vec<String>>> {
+        if let Some((read_at, names)) = self.cached.lock().unwrap().get(&registry.name) {
+            if read_at.elapsed() < REFRESH {
+                return Ok(Arc::clone(names));
+            }
+        }
+
+        let path = registry.index_path.clone();
+        let names = tokio::task::spawn_blocking(move || {
+            let mut names = Vec::new();
+            read_names(Path::new(&path), &mut names)?;
+            Ok::<_, io::Error>(Arc::new(names))
+        })
+        .await??;
+
+        self.cached
+            .lock()
+            .unwrap()
+            .insert(registry.name.clone(), (Instant::now(), Arc::clone(&names)));
+
+        Ok(names)
+    }
+}
+
+/// Collects names of all crate files in the index directory `dir`.
+fn read_names(dir: &Path, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        // `.git`, `.github`, etc
+        if name.starts_with('.') || name == "config.json" {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            read_names(&entry.path(), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds up to `limit` names starting with `prefix`, the shortest first. Case
+/// and `-`/`_` are ignored.
+fn starting_with(prefix: &str, names: &[String], limit: usize) -> Vec<String> {
+    let prefix = normalize_name(prefix);
+
+    let mut found: Vec<_> = names
+        .iter()
+        .filter(|name| normalize_name(name).starts_with(&prefix))
+        .map(|name| (name.len(), name))
+        .collect();
+
+    found.sort();
+    found
+        .into_iter()
+        .take(limit)
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// Finds up to `limit` names nearest to `name`. Case and `-`/`_` are ignored,
+/// the same way crates.io does when checking for name collisions.
+fn nearest(name: &str, names: &[String], limit: usize) -> Vec<String> {
+    let name = normalize_name(name);
+
+    // Allow more typos in longer names
+    let max = match name.len() {
+        0..=3 => 1,
+        4..=8 => 2,
+        _ => 3,
+    };
+
+    let mut found: Vec<_> = names
+        .iter()
+        .filter(|candidate| candidate.len().abs_diff(name.len()) <= max)
+        .filter_map(|candidate| {
+            let distance = distance(&name, &normalize_name(candidate));
+            (distance <= max).then_some((distance, candidate))
+        })
+        .collect();
+
+    found.sort();
+    found
+        .into_iter()
+        .take(limit)
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut row: Vec<_> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev + usize::from(ca != cb);
+            prev = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(prev + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distance, nearest, starting_with};
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn levenshtein() {
+        assert_eq!(distance("serde", "serde"), 0);
+        assert_eq!(distance("serde", "sedre"), 2);
+        assert_eq!(distance("tokio", "toki"), 1);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn nearest_ignores_separators_and_case() {
+        let known = names(&["serde", "serde_json", "serde_yaml", "Inflector"]);
+
+        assert_eq!(nearest("serde-json", &known, 3), ["serde_json"]);
+        assert_eq!(nearest("inflector", &known, 3), ["Inflector"]);
+    }
+
+    #[test]
+    fn nearest_thresholds() {
+        // Short names allow a single typo
+        let known = names(&["log", "l"]);
+        assert_eq!(nearest("lgo", &known, 3), Vec::<String>::new());
+        assert_eq!(nearest("lox", &known, 3), ["log"]);
+
+        // Medium ones two
+        let known = names(&["tokio"]);
+        assert_eq!(nearest("tkoio", &known, 3), ["tokio"]);
+        assert_eq!(nearest("tkoi", &known, 3), Vec::<String>::new());
+
+        // And long ones three
+        let known = names(&["serde_json"]);
+        assert_eq!(nearest("sorde_jsax", &known, 3), ["serde_json"]);
+        assert_eq!(nearest("sorde_jxax", &known, 3), Vec::<String>::new());
+    }
+
+    #[test]
+    fn nearest_limit() {
+        let known = names(&["serdf", "serde", "sorde", "serd", "xerde"]);
+
+        // The nearest first, then by name
+        assert_eq!(nearest("serde", &known, 3), ["serde", "serd", "serdf"]);
+    }
+
+    #[test]
+    fn prefix() {
+        let known = names(&["tokio_util", "Tokio", "tokio-stream", "toml", "tokio-io"]);
+
+        assert_eq!(
+            starting_with("TOKIO", &known, 3),
+            ["Tokio", "tokio-io", "tokio_util"]
+        );
+        assert_eq!(starting_with("tokio_s", &known, 3), ["tokio-stream"]);
+        assert_eq!(starting_with("tokio", &known, 10).len(), 4);
+    }
+}
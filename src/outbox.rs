@@ -0,0 +1,236 @@
+//! Outbox, i.e. notifications waiting to be sent.
+//!
+//! Notifications are stored in the database before they are sent, so they
+//! survive restarts, and are sent by a single worker which respects telegram
+//! rate limits (<https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this>).
+//!
+//! Aggregated channel posts are sent once and then edited, the outbox keeps
+//! only their latest version.
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use futures::Future;
+use teloxide::{prelude::*, types::MessageId, ApiError, RequestError};
+
+use crate::{
+    cfg::Config,
+    db::{ChatStatus, Database, Outgoing},
+    Bot,
+};
+
+/// Maximum number of messages taken from the outbox at once.
+const BATCH_SIZE: i32 = 100;
+
+/// Delay before checking the outbox again when nothing could be sent.
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Minimal delay between messages to the same private chat.
+const PRIVATE_CHAT_DELAY: Duration = Duration::from_secs(1);
+
+/// Minimal delay between messages to the same group or channel (telegram
+/// allows 20 messages per minute).
+const GROUP_CHAT_DELAY: Duration = Duration::from_secs(3);
+
+/// Number of failed attempts after which a message is dropped.
+const MAX_ATTEMPTS: i32 = 5;
+
+/// Sends messages from the outbox until `stop` resolves.
+pub async fn run(mut stop: impl Future + Unpin, bot: &Bot, db: &Database, cfg: &Config) {
+    let mut limits = Limits::new(cfg.broadcast_delay_millis.into());
+
+    loop {
+        let batch = db.outbox_batch(BATCH_SIZE).await.unwrap_or_else(|err| {
+            log::error!("db error while getting outbox: {}", err);
+            Vec::new()
+        });
+
+        // Chats whose messages were skipped in this round, their later messages
+        // are skipped too, to keep the order
+        let mut skipped = HashSet::new();
+        let mut sent = false;
+
+        for msg in batch {
+            if skipped.contains(&msg.chat_id) || !limits.ready(msg.chat_id) {
+                skipped.insert(msg.chat_id);
+                continue;
+            }
+
+            if sleep(limits.global_wait(), &mut stop).await {
+                return;
+            }
+
+            match send(bot, &msg).await {
+                Ok(message_id) => {
+                    limits.sent(msg.chat_id);
+                    sent = true;
+
+                    // The post was sent for the first time, its next versions are edits
+                    if let Some((post_id, None)) = msg.post {
+                        db.set_post_message(post_id, message_id)
+                            .await
+                            .unwrap_or_else(|err| {
+                                log::error!("db error while saving post#{post_id}: {}", err)
+                            });
+                    }
+
+                    remove(db, &msg).await;
+                }
+                Err(RequestError::RetryAfter(after)) => {
+                    log::warn!("hit flood control, waiting for {after:?}");
+                    skipped.insert(msg.chat_id);
+
+                    if sleep(after, &mut stop).await {
+                        return;
+                    }
+                }
+                // The group was upgraded to a supergroup, the message will be sent to it later
+                Err(RequestError::MigrateToChatId(to)) => {
+                    skipped.insert(msg.chat_id);
+
+                    db.migrate_chat(msg.chat_id, ChatId(to))
+                        .await
+                        .unwrap_or_else(|err| {
+                            log::error!("db error while migrating {}: {}", msg.chat_id, err)
+                        });
+                }
+                // The chat is marked as inactive, other messages to it are dropped too
+                Err(RequestError::Api(
+                    err @ (ApiError::BotBlocked
+                    | ApiError::UserDeactivated
+                    | ApiError::BotKicked
+                    | ApiError::BotKickedFromSupergroup),
+                )) => {
+                    log::info!("couldn't send message to {}: {}", msg.chat_id, err);
+                    skipped.insert(msg.chat_id);
+
+                    let status = match err {
+                        ApiError::BotBlocked | ApiError::UserDeactivated => ChatStatus::Blocked,
+                        _ => ChatStatus::Kicked,
+                    };
+                    if let Err(err) = db.set_chat_status(msg.chat_id, status).await {
+                        log::error!("db error while deactivating {}: {}", msg.chat_id, err);
+                    }
+                }
+                Err(
+                    err @ (RequestError::Network(_)
+                    | RequestError::Io(_)
+                    | RequestError::InvalidJson { .. }),
+                ) if msg.attempts + 1 < MAX_ATTEMPTS => {
+                    log::warn!(
+                        "error while trying to send message to {}: {}",
+                        msg.chat_id,
+                        err
+                    );
+                    skipped.insert(msg.chat_id);
+
+                    db.postpone_outgoing(msg.id, cfg.retry_delay.0)
+                        .await
+                        .unwrap_or_else(|err| {
+                            log::error!("db error while postponing message: {}", err)
+                        });
+                }
+                // Retrying won't help (e.g. the bot was blocked)
+                Err(err) => {
+                    log::error!("couldn't send message to {}: {}", msg.chat_id, err);
+                    remove(db, &msg).await;
+                }
+            }
+        }
+
+        if !sent && sleep(IDLE_DELAY, &mut stop).await {
+            return;
+        }
+    }
+}
+
+/// Sends the message (or edits the post), returns id of the message.
+async fn send(bot: &Bot, msg: &Outgoing) -> Result<MessageId, RequestError> {
+    if let Some((_, Some(message_id))) = msg.post {
+        let res = bot
+            .edit_message_text(msg.chat_id, message_id, &msg.text)
+            .disable_web_page_preview(true)
+            .await;
+
+        return match res {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(message_id),
+            Err(err) => Err(err),
+        };
+    }
+
+    let request = bot
+        .send_message(msg.chat_id, &msg.text)
+        .disable_web_page_preview(true)
+        .disable_notification(msg.silent);
+
+    let message = match &msg.keyboard {
+        Some(keyboard) => request.reply_markup(keyboard.clone()).await?,
+        None => request.await?,
+    };
+
+    Ok(message.id)
+}
+
+async fn remove(db: &Database, msg: &Outgoing) {
+    db.remove_outgoing(msg.id)
+        .await
+        .unwrap_or_else(|err| log::error!("db error while removing sent message: {}", err));
+}
+
+/// Sleeps for `duration`, returns `true` if `stop` resolved first.
+async fn sleep(duration: Duration, stop: &mut (impl Future + Unpin)) -> bool {
+    tokio::select! {
+        biased;
+        _ = stop => true,
+        _ = tokio::time::sleep(duration) => false,
+    }
+}
+
+/// Telegram rate limits.
+struct Limits {
+    /// Minimal delay between any two messages
+    global: Duration,
+    /// When the next message can be sent
+    next: Instant,
+    /// When the next message can be sent to a chat, only for chats which
+    /// recently got a message
+    chats: HashMap<ChatId, Instant>,
+}
+
+impl Limits {
+    fn new(global: Duration) -> Self {
+        Self {
+            global,
+            next: Instant::now(),
+            chats: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if a message can be sent to the chat now (not counting
+    /// the global limit).
+    fn ready(&self, chat_id: ChatId) -> bool {
+        match self.chats.get(&chat_id) {
+            Some(&next) => next <= Instant::now(),
+            None => true,
+        }
+    }
+
+    /// How long to wait before sending the next message.
+    fn global_wait(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    fn sent(&mut self, chat_id: ChatId) {
+        let now = Instant::now();
+        let delay = if chat_id.is_user() {
+            PRIVATE_CHAT_DELAY
+        } else {
+            GROUP_CHAT_DELAY
+        };
+
+        self.next = now + self.global;
+        self.chats.insert(chat_id, now + delay);
+        self.chats.retain(|_, next| *next > now);
+    }
+}